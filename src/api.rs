@@ -0,0 +1,288 @@
+//! Helpers for talking to the Spotify API.
+
+use crate::cache::CacheSender;
+use crate::concurrency;
+use log::warn;
+use rspotify::{
+    ClientCredsSpotify, ClientError, ClientResult,
+    http::{HttpError, Query},
+    model::{AlbumId, ArtistId, FullTrack, TrackId},
+    prelude::*,
+};
+use serde::Deserialize;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// CHUNK_SIZE is the most tracks or artists Spotify accepts in one request.
+pub const CHUNK_SIZE: usize = 50;
+
+/// ALBUM_CHUNK_SIZE is the most albums Spotify accepts in one request.
+pub const ALBUM_CHUNK_SIZE: usize = 20;
+
+/// FIRST_BACKOFF is how long [with_retries] waits before its first retry of a transient error, doubling each time.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// TOKEN_REFRESH is held while checking and refreshing the access token, so requests that find it expired at the
+/// same time refresh it once between them instead of each requesting a new one.
+static TOKEN_REFRESH: Mutex<()> = Mutex::const_new(());
+
+/// current_access_token returns the access token `spotify` is using, if it has one, and whether it has expired.
+async fn current_access_token(spotify: &ClientCredsSpotify) -> Option<(String, bool)> {
+    let token = spotify.get_token();
+    let token = token.lock().await.ok()?;
+    token.as_ref().map(|token| (token.access_token.clone(), token.is_expired()))
+}
+
+/// refresh_token requests a new access token for `spotify` if it has none, it has expired, or it's still
+/// `rejected_token` (one Spotify just answered 401 to). Whoever gets [TOKEN_REFRESH] first refreshes; everyone else
+/// then finds the new token and leaves it be.
+async fn refresh_token(spotify: &ClientCredsSpotify, rejected_token: Option<&str>) -> ClientResult<()> {
+    let _refreshing = TOKEN_REFRESH.lock().await;
+    let stale = match current_access_token(spotify).await {
+        Some((access_token, expired)) => expired || rejected_token == Some(access_token.as_str()),
+        None => true,
+    };
+    if stale {
+        spotify.request_token().await?;
+    }
+    Ok(())
+}
+
+/// is_unauthorized returns whether `e` is Spotify answering 401 Unauthorized, i.e. rejecting the access token.
+fn is_unauthorized(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Http(e) if matches!(**e, HttpError::StatusCode(ref response) if response.status().as_u16() == 401)
+    )
+}
+
+/// retry_after returns how long Spotify asked to be left alone for if `e` is a 429 with a Retry-After header.
+fn retry_after(e: &ClientError) -> Option<Duration> {
+    let ClientError::Http(e) = e else {
+        return None;
+    };
+    let HttpError::StatusCode(response) = &**e else {
+        return None;
+    };
+    let secs = response.headers().get("retry-after")?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+/// is_transient returns whether retrying the request that failed with `e` might succeed: rate limiting, server
+/// errors, and network errors are; anything else (a bad request, an invalid ID, bad credentials) isn't.
+fn is_transient(e: &ClientError) -> bool {
+    match e {
+        ClientError::Http(e) => match &**e {
+            HttpError::StatusCode(response) => {
+                let status = response.status();
+                status.as_u16() == 429 || status.is_server_error()
+            }
+            _ => true,
+        },
+        ClientError::Io(_) => true,
+        _ => false,
+    }
+}
+
+/// with_retries runs the Spotify request `op` on `spotify`, retrying up to `retries` times while it fails with a
+/// [transient](is_transient) error. A 429 waits as long as its Retry-After header says; anything else waits
+/// [FIRST_BACKOFF], doubling with each retry. The last error is returned once the retries run out, and any other
+/// error immediately.
+/// The access token is refreshed before sending the request if it has expired, and once more if Spotify rejects it
+/// anyway, so long runs outlive the token's hour.
+pub async fn with_retries<T, F: Future<Output = ClientResult<T>>>(
+    spotify: &ClientCredsSpotify,
+    retries: u32,
+    mut op: impl FnMut() -> F,
+) -> ClientResult<T> {
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 0;
+    let mut reauthorized = false;
+    loop {
+        if current_access_token(spotify).await.is_none_or(|(_, expired)| expired) {
+            refresh_token(spotify, None).await?;
+        }
+        let sent_token = current_access_token(spotify).await.map(|(access_token, _)| access_token);
+        match op().await {
+            Err(e) if !reauthorized && is_unauthorized(&e) => {
+                warn!("Spotify rejected the access token, requesting a new one");
+                reauthorized = true;
+                refresh_token(spotify, sent_token.as_deref()).await?;
+            }
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                let delay = match retry_after(&e) {
+                    Some(retry_after) => retry_after,
+                    None => {
+                        let delay = backoff;
+                        backoff *= 2;
+                        delay
+                    }
+                };
+                match concurrency::is_rate_limited(&e) {
+                    true => warn!("Rate limited by Spotify, retrying in {delay:?} ({attempt}/{retries})"),
+                    false => warn!("Spotify request failed ({e}), retrying in {delay:?} ({attempt}/{retries})"),
+                }
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Tracks is the body of a `tracks` response. Spotify answers a track it no longer has with `null` in its place
+/// rather than erroring, which rspotify's own `tracks` can't deserialize, so the whole chunk would fail on one.
+#[derive(Deserialize)]
+struct Tracks {
+    tracks: Vec<Option<FullTrack>>,
+}
+
+/// parse_tracks parses the body of a `tracks` response, keeping a `None` for each track Spotify returned `null` for.
+fn parse_tracks(body: &str) -> ClientResult<Vec<Option<FullTrack>>> {
+    Ok(serde_json::from_str::<Tracks>(body)?.tracks)
+}
+
+/// fetch_tracks looks up one chunk of at most [CHUNK_SIZE] `tracks` on Spotify, returning one entry per requested
+/// ID in the same order: `None` where Spotify no longer has the track.
+pub async fn fetch_tracks(
+    spotify: &ClientCredsSpotify,
+    tracks: &[TrackId<'static>],
+) -> ClientResult<Vec<Option<FullTrack>>> {
+    let ids = tracks.iter().map(|track| track.id()).collect::<Vec<_>>().join(",");
+    let body = spotify.api_get(&format!("tracks/?ids={ids}"), &Query::new()).await?;
+    parse_tracks(&body)
+}
+
+/// align_tracks pairs each track in a [fetch_tracks] response with the ID it was requested as.
+/// The response has an entry for each requested ID in order, `None` where Spotify no longer has the track, so they're
+/// matched by position; a relinked track keeps the ID it was requested as even though its own ID differs.
+/// Returns the matched `(requested ID, track)` pairs and the requested IDs Spotify returned `null` for (or, should
+/// the response come back short, didn't return at all).
+pub fn align_tracks(
+    requested: &[TrackId<'static>],
+    response: Vec<Option<FullTrack>>,
+) -> (Vec<(TrackId<'static>, FullTrack)>, Vec<TrackId<'static>>) {
+    if response.len() != requested.len() {
+        warn!("Spotify returned {} tracks for {} requested IDs", response.len(), requested.len());
+    }
+    let mut matched = Vec::with_capacity(response.len());
+    let mut missing = Vec::new();
+    let mut response = response.into_iter();
+    for id in requested {
+        match response.next().flatten() {
+            Some(track) => matched.push((id.clone(), track)),
+            None => missing.push(id.clone()),
+        }
+    }
+    (matched, missing)
+}
+
+/// fetch_artist_genres looks up the genres of one chunk of at most [CHUNK_SIZE] `artists` on Spotify, also sending
+/// each to `cache` if given.
+/// Transient errors are retried up to `retries` times through [with_retries].
+pub async fn fetch_artist_genres(
+    spotify: &ClientCredsSpotify,
+    artists: Vec<ArtistId<'static>>,
+    cache: Option<&CacheSender>,
+    retries: u32,
+) -> ClientResult<Vec<(ArtistId<'static>, Vec<String>)>> {
+    let response = with_retries(spotify, retries, || spotify.artists(artists.clone())).await?;
+    Ok(response
+        .into_iter()
+        .map(|artist| {
+            if let Some(cache) = cache {
+                // This only fails if the writer already stopped on an error saving the cache, which it reports
+                // when it's finished; the genres are still returned either way
+                let _ = cache.send((artist.id.clone(), artist.genres.clone()));
+            }
+            (artist.id, artist.genres)
+        })
+        .collect())
+}
+
+/// fetch_album_genres looks up the genres of one chunk of at most [ALBUM_CHUNK_SIZE] `albums` on Spotify.
+/// Transient errors are retried up to `retries` times through [with_retries].
+pub async fn fetch_album_genres(
+    spotify: &ClientCredsSpotify,
+    albums: Vec<AlbumId<'static>>,
+    retries: u32,
+) -> ClientResult<Vec<(AlbumId<'static>, Vec<String>)>> {
+    let response = with_retries(spotify, retries, || spotify.albums(albums.clone(), None)).await?;
+    Ok(response.into_iter().map(|album| (album.id, album.genres)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn track(id: &str) -> Value {
+        json!({
+            "album": {
+                "artists": [],
+                "available_markets": [],
+                "external_urls": {},
+                "images": [],
+                "name": "Album",
+            },
+            "artists": [],
+            "available_markets": [],
+            "disc_number": 1,
+            "duration_ms": 1000,
+            "explicit": false,
+            "external_ids": {},
+            "external_urls": {},
+            "id": id,
+            "is_local": false,
+            "name": id,
+            "popularity": 0,
+            "track_number": 1,
+        })
+    }
+
+    fn ids(ids: &[&str]) -> Vec<TrackId<'static>> {
+        ids.iter().map(|id| TrackId::from_id(id.to_string()).unwrap()).collect()
+    }
+
+    #[test]
+    fn null_in_the_middle_leaves_later_tracks_their_own_ids() {
+        let requested = ids(&["0000000000000000000001", "0000000000000000000002", "0000000000000000000003"]);
+        // Spotify no longer has the second track and answers null in its place
+        let body = json!({
+            "tracks": [track("0000000000000000000001"), null, track("0000000000000000000003")],
+        })
+        .to_string();
+        let response = parse_tracks(&body).unwrap();
+        let (matched, missing) = align_tracks(&requested, response);
+        let matched: Vec<(&str, &str)> =
+            matched.iter().map(|(id, track)| (id.id(), track.id.as_ref().unwrap().id())).collect();
+        assert_eq!(
+            matched,
+            [
+                ("0000000000000000000001", "0000000000000000000001"),
+                ("0000000000000000000003", "0000000000000000000003"),
+            ]
+        );
+        assert_eq!(missing, ids(&["0000000000000000000002"]));
+    }
+
+    #[test]
+    fn relinked_track_keeps_the_requested_id() {
+        let requested = ids(&["0000000000000000000001", "0000000000000000000002"]);
+        let body = json!({ "tracks": [track("0000000000000000000001"), track("0000000000000000000009")] }).to_string();
+        let (matched, missing) = align_tracks(&requested, parse_tracks(&body).unwrap());
+        assert_eq!(matched[1].0.id(), "0000000000000000000002");
+        assert_eq!(matched[1].1.name, "0000000000000000000009");
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn short_response_reports_the_rest_missing() {
+        let requested = ids(&["0000000000000000000001", "0000000000000000000002"]);
+        let body = json!({ "tracks": [track("0000000000000000000001")] }).to_string();
+        let (matched, missing) = align_tracks(&requested, parse_tracks(&body).unwrap());
+        assert_eq!(matched.len(), 1);
+        assert_eq!(missing, ids(&["0000000000000000000002"]));
+    }
+}
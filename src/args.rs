@@ -0,0 +1,275 @@
+//! Command-line arguments.
+
+use crate::api::CHUNK_SIZE;
+use crate::genres::{CaseFoldLocale, CombineMode, GenreScope};
+use crate::rename;
+use crate::scan::{self, FolderFilter};
+use clap::{Parser, Subcommand, builder::RangedU64ValueParser};
+use std::path::PathBuf;
+use url::Url;
+
+/// Args holds the options parsed from the command line.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The folder Zotify downloads into, with a folder per artist containing a folder per album.
+    #[arg(env = "BASE_PATH", value_name = "BASE_PATH")]
+    pub base_path: Option<PathBuf>,
+
+    /// The client ID of your Spotify API key.
+    #[arg(long, env = "RSPOTIFY_CLIENT_ID", value_name = "ID")]
+    pub client_id: Option<String>,
+
+    /// The client secret of your Spotify API key.
+    #[arg(long, env = "RSPOTIFY_CLIENT_SECRET", value_name = "SECRET", hide_env_values = true)]
+    pub client_secret: Option<String>,
+
+    /// How many tracks or artists to look up per Spotify request, at most 50.
+    #[arg(long, default_value_t = CHUNK_SIZE, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..=CHUNK_SIZE as u64))]
+    pub chunk_size: usize,
+
+    /// Only scan folders whose name (e.g. the artist or album folder) matches PATTERN: a glob like "Radiohead*" if it
+    /// has any of *?[, otherwise a substring. Either way case doesn't matter.
+    #[arg(long, value_name = "PATTERN", value_parser = scan::parse_filter, conflicts_with = "flat_dir")]
+    pub filter: Option<FolderFilter>,
+
+    /// Where artist genres are cached between runs.
+    #[arg(long, value_name = "PATH", default_value = "genre_cache.json")]
+    pub cache_file: PathBuf,
+
+    /// Look up every artist on Spotify again instead of using their cached genres, updating the cache.
+    #[arg(long)]
+    pub refresh_cache: bool,
+
+    /// Rename each tagged file after its Spotify metadata, e.g. "{track_number} - {title}", and update .song_ids to
+    /// match. Fields: {title}, {artist}, {artists}, {album}, {track_number}, {disc_number}.
+    #[arg(long, value_name = "PATTERN", value_parser = rename::parse_pattern)]
+    pub rename_pattern: Option<String>,
+
+    /// Write a JSON manifest of every file modified by this run to PATH.
+    #[arg(long, value_name = "PATH")]
+    pub changes: Option<PathBuf>,
+
+    /// Write a JSON report of the run, including every file that couldn't be tagged and why, to PATH.
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+
+    /// Write a JSON list of every track matched to more than one file to PATH, with the file kept for tagging
+    /// (the highest bit rate one) and the redundant ones.
+    #[arg(long, value_name = "PATH")]
+    pub report_duplicates: Option<PathBuf>,
+
+    /// Move the redundant files of tracks matched to more than one file into DIR, keeping their paths relative to
+    /// BASE_PATH. Nothing is deleted.
+    #[arg(long, value_name = "DIR")]
+    pub quarantine_duplicates: Option<PathBuf>,
+
+    /// Tag the loose files directly inside DIR instead of scanning BASE_PATH for folders with
+    /// .song_ids files. Each file is matched by a Spotify track ID in its tags, or else by searching for its title
+    /// and artist.
+    #[arg(long, value_name = "DIR", conflicts_with = "reprocess_errors")]
+    pub flat_dir: Option<PathBuf>,
+
+    /// Write a JSON list of the files in album folders that no .song_ids entry refers to to PATH.
+    #[arg(long, value_name = "PATH")]
+    pub report_unreferenced: Option<PathBuf>,
+
+    /// Skip scanning and Spotify and only retry writing the files that failed in the report at PATH,
+    /// using the genres recorded there.
+    #[arg(long, value_name = "PATH")]
+    pub reprocess_errors: Option<PathBuf>,
+
+    /// Copy each original into DIR, at its path relative to BASE_PATH, before tagging it. A file that can't be backed
+    /// up isn't touched. Existing backups are never overwritten.
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Read each file's genre tag back after replacing it and count it as failed if it isn't what was written,
+    /// restoring the original from --backup-dir if set.
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Remux every file to a temp file first and only replace the originals once all of them succeed.
+    /// Needs enough free space for a second copy of every file being tagged.
+    #[arg(long)]
+    pub transactional: bool,
+
+    /// Tag each track with its album's genres when Spotify has any, only falling back to its artists' genres for
+    /// albums without.
+    #[arg(long)]
+    pub prefer_album_genres: bool,
+
+    /// How to combine the genres of tracks with more than one artist.
+    #[arg(long, value_enum, default_value_t)]
+    pub artist_genre_combine: CombineMode,
+
+    /// Only take the first N genres of each artist (in Spotify's order) before combining them.
+    #[arg(long, value_name = "N")]
+    pub max_genres_per_artist: Option<usize>,
+
+    /// Tag each track with at most its first N distinct genres, once --genre-map and --genre-scope are applied.
+    #[arg(long, value_name = "N")]
+    pub max_genres: Option<usize>,
+
+    /// Also write an extended M3U playlist of the processed tracks, with #EXTGENRE lines, to PATH.
+    #[arg(long, value_name = "PATH")]
+    pub write_m3u: Option<PathBuf>,
+
+    /// Whether to tag each track with its own genres, its album's, or its own falling back to its album's.
+    #[arg(long, value_enum, default_value_t)]
+    pub genre_scope: GenreScope,
+
+    /// The string to join a track's genres with in its genre tag.
+    #[arg(long, default_value = ",", value_name = "DELIMITER", conflicts_with_all = ["genre_multi_value", "genres_delimiter_detect"])]
+    pub genre_delimiter: String,
+
+    /// Write each genre as its own genre tag instead of joining them into one, in Ogg and FLAC files (MP3 and MP4
+    /// files get them joined with ";").
+    #[arg(long, conflicts_with = "genres_delimiter_detect")]
+    pub genre_multi_value: bool,
+
+    /// Instead of joining genres with --genre-delimiter, pick the first delimiter that appears in none of the genres
+    /// being written, falling back to one genre tag per genre if there isn't one.
+    #[arg(long)]
+    pub genres_delimiter_detect: bool,
+
+    /// Map each track's genres through the `source -> target` rules in the file at PATH before writing them.
+    #[arg(long, value_name = "PATH")]
+    pub genre_map: Option<PathBuf>,
+
+    /// Drop genres no --genre-map rule matches instead of keeping them as they are.
+    #[arg(long, requires = "genre_map")]
+    pub drop_unmapped_genres: bool,
+
+    /// Whose case rules to use when lowercasing genres.
+    #[arg(long, value_enum, default_value_t)]
+    pub case_fold_locale: CaseFoldLocale,
+
+    /// Pipe each track's genres through this shell command before writing. See the README for the JSON contract.
+    #[arg(long, value_name = "COMMAND")]
+    pub genre_filter_cmd: Option<String>,
+
+    /// Seconds to let --genre-filter-cmd run for each track before killing it.
+    #[arg(long, default_value_t = 10)]
+    pub genre_filter_timeout: u64,
+
+    /// Scan, then print how many Spotify requests fetching genres would take and roughly how long, and exit
+    /// without fetching or writing anything.
+    #[arg(long)]
+    pub estimate: bool,
+
+    /// How many times to retry a Spotify request that was rate limited or failed transiently before counting its
+    /// tracks as failed.
+    #[arg(long, default_value_t = 5)]
+    pub api_retries: u32,
+
+    /// How many chunks of tracks to look up on Spotify at once.
+    #[arg(long, default_value_t = 8, conflicts_with = "concurrency_auto")]
+    pub fetch_concurrency: usize,
+
+    /// Probe how many concurrent lookups Spotify allows before getting rate limited and use that instead of
+    /// --fetch-concurrency. The result is remembered per client ID in --concurrency-file.
+    #[arg(long)]
+    pub concurrency_auto: bool,
+
+    /// Where --concurrency-auto remembers the concurrency it probed for each client ID.
+    #[arg(long, value_name = "PATH", default_value = "concurrency.json")]
+    pub concurrency_file: PathBuf,
+
+    /// Send Spotify API requests to this base URL instead of Spotify's, e.g. for a mock server or a proxy.
+    #[arg(long, env = "SPOTIFY_API_BASE_URL", value_name = "URL", value_parser = parse_api_base_url)]
+    pub api_base_url: Option<String>,
+
+    /// Print the most common genres across the library once they're resolved.
+    #[arg(long)]
+    pub genre_stats: bool,
+
+    /// How many genres --genre-stats prints.
+    #[arg(long, default_value_t = 20, value_name = "N")]
+    pub genre_stats_top: usize,
+
+    /// Also write every genre's track count to PATH, one "count<TAB>genre" line each, with --genre-stats.
+    #[arg(long, value_name = "PATH", requires = "genre_stats")]
+    pub genre_stats_file: Option<PathBuf>,
+
+    /// Record track IDs, paths, and genres in the SQLite database at PATH instead of modifying any files.
+    #[arg(long, value_name = "PATH")]
+    pub sqlite: Option<PathBuf>,
+
+    /// Run the scan, Spotify lookups, and genre aggregation, then print the genre tag each file would get instead of
+    /// writing anything.
+    #[arg(long, env = "DRY_RUN", conflicts_with = "sqlite")]
+    pub dry_run: bool,
+
+    /// Rewrite files even if their state hash shows they already have exactly these genres.
+    #[arg(long)]
+    pub ignore_state_hash: bool,
+
+    /// Never rewrite a file this tool has tagged before, even if its genres would change.
+    /// Takes precedence over --ignore-state-hash.
+    #[arg(long)]
+    pub only_untouched: bool,
+
+    /// Record when each album was last processed in PATH.
+    #[arg(long, value_name = "PATH")]
+    pub last_run_file: Option<PathBuf>,
+
+    /// How many files to remux at once. Defaults to the number of CPU cores.
+    #[arg(long, value_name = "N")]
+    pub write_concurrency: Option<usize>,
+
+    /// How many times to retry writing a temp file or renaming it over the original on transient filesystem errors
+    /// (e.g. a file briefly locked by an antivirus scanner) before counting the file as failed.
+    #[arg(long, default_value_t = 3)]
+    pub fs_retries: u32,
+
+    /// How many times to retry when the library looks disconnected (e.g. a dropped network mount) before giving up.
+    #[arg(long, default_value_t = 5)]
+    pub mount_retries: u32,
+
+    /// Seconds to wait before the first retry when the library looks disconnected; doubles on each retry.
+    #[arg(long, default_value_t = 5)]
+    pub mount_retry_delay: u64,
+
+    /// Only tag files modified since their album was last processed, according to --last-run-file.
+    #[arg(long, requires = "last_run_file")]
+    pub only_new: bool,
+
+    /// Only process the first N matched files, sorted by path, e.g. for a quick trial run. They're tagged for real.
+    #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub limit: Option<usize>,
+
+    /// Don't log each skipped file; they're still counted in the summary.
+    #[arg(long)]
+    pub quiet_skip: bool,
+}
+
+/// Command is an alternative mode to run instead of tagging.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scan the library and print a histogram of container/codec combinations without writing anything.
+    Formats,
+    /// Look up the genres of a list of artists and add them to the cache, without scanning or writing anything.
+    WarmCache {
+        /// A file with one Spotify artist ID, URI, or URL per line.
+        #[arg(long, value_name = "PATH")]
+        artists: PathBuf,
+    },
+}
+
+/// parse_api_base_url checks that `url` is an absolute http(s) URL and gives it the trailing slash rspotify
+/// expects when joining endpoint paths onto it.
+fn parse_api_base_url(url: &str) -> Result<String, String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid URL: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("expected an http or https URL, got {}", parsed.scheme()));
+    }
+    let mut url = parsed.to_string();
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    Ok(url)
+}
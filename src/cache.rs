@@ -0,0 +1,128 @@
+//! The persistent cache of artist genres, so artists already looked up on Spotify don't have to be looked up again.
+//!
+//! Genres are stored [normalized](crate::genres::normalize_genre), and normalized again on load in case the file
+//! was written with another `--case-fold-locale`, so cached and freshly fetched genres always compare equal when they
+//! should.
+//!
+//! The cache file isn't behind a `std::sync::Mutex` held across disk writes inside the fetch tasks: it's only ever
+//! touched by a single writer thread ([CacheWriter]) that owns it, and the tasks send it entries over an `mpsc`
+//! channel. The writer flushes them in batches of [FLUSH_EVERY], so fetch tasks never block a runtime thread on disk
+//! I/O and flushing often doesn't cost throughput.
+
+use crate::changes;
+use crate::genres::{self, CaseFoldLocale};
+use crate::json;
+use anyhow::Result;
+use rspotify::model::ArtistId;
+use rspotify::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+
+/// FLUSH_EVERY is how many new entries the writer collects before writing the cache file out again.
+const FLUSH_EVERY: usize = 500;
+
+/// CachedArtist is an artist's genres as Spotify returned them, and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedArtist {
+    pub genres: Vec<String>,
+    pub fetched_at: u64,
+}
+
+/// ArtistCache is the cached genres of each artist, keyed by artist ID.
+pub type ArtistCache = HashMap<String, CachedArtist>;
+
+/// CacheSender sends freshly fetched artist genres to a [CacheWriter].
+pub type CacheSender = mpsc::UnboundedSender<(ArtistId<'static>, Vec<String>)>;
+
+/// load reads the cache at `path`, or returns an empty one if it doesn't exist yet, normalizing every entry's genres
+/// with `locale`.
+pub fn load(path: &Path, locale: CaseFoldLocale) -> Result<ArtistCache> {
+    let mut cache: ArtistCache = json::read_or_default(path)?;
+    for cached in cache.values_mut() {
+        cached.genres = genres::normalize_genres_in_order(&cached.genres, locale);
+    }
+    Ok(cache)
+}
+
+/// save atomically writes `cache` as JSON to `path`.
+pub fn save(path: &Path, cache: &ArtistCache) -> Result<()> {
+    json::write_atomic(path, cache)
+}
+
+/// CacheWriter owns the cache file on a blocking thread and adds every entry sent to it, normalized, writing the file
+/// out every [FLUSH_EVERY] entries and once more when it's finished.
+pub struct CacheWriter {
+    sender: CacheSender,
+    handle: JoinHandle<Result<ArtistCache>>,
+}
+
+impl CacheWriter {
+    /// spawn starts a writer that adds to `cache`, normalizing with `locale`, and writes it to `path`.
+    pub fn spawn(path: PathBuf, mut cache: ArtistCache, locale: CaseFoldLocale) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(ArtistId<'static>, Vec<String>)>();
+        let handle = task::spawn_blocking(move || {
+            let mut unflushed = 0;
+            while let Some((artist, genres)) = receiver.blocking_recv() {
+                cache.insert(
+                    artist.id().to_owned(),
+                    CachedArtist {
+                        genres: genres::normalize_genres_in_order(&genres, locale),
+                        fetched_at: changes::unix_timestamp(),
+                    },
+                );
+                unflushed += 1;
+                if unflushed >= FLUSH_EVERY {
+                    save(&path, &cache)?;
+                    unflushed = 0;
+                }
+            }
+            save(&path, &cache)?;
+            Ok(cache)
+        });
+        CacheWriter { sender, handle }
+    }
+
+    /// sender returns a new handle to send entries to the writer with.
+    pub fn sender(&self) -> CacheSender {
+        self.sender.clone()
+    }
+
+    /// finish waits for every entry sent so far to be written and returns the final cache.
+    /// Any [sender](CacheWriter::sender)s still alive must be dropped first, or this never returns.
+    pub async fn finish(self) -> Result<ArtistCache> {
+        drop(self.sender);
+        self.handle.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genres::CombineMode;
+    use std::fs;
+
+    #[tokio::test]
+    async fn cached_and_fetched_genres_merge_despite_formatting() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("cache.json");
+        fs::write(&path, r#"{"cached": {"genres": ["Indie ", "Rock"], "fetched_at": 0}}"#).unwrap();
+        let cache = load(&path, CaseFoldLocale::Unicode).unwrap();
+        assert_eq!(cache["cached"].genres, ["indie", "rock"]);
+
+        let writer = CacheWriter::spawn(path, cache, CaseFoldLocale::Unicode);
+        let fetched = ArtistId::from_id("0000000000000000000001").unwrap();
+        writer.sender().send((fetched.clone(), vec!["indie".to_owned()])).unwrap();
+        let cache = writer.finish().await.unwrap();
+
+        let combined = genres::combine_artist_genres(
+            CombineMode::Union,
+            &[cache["cached"].genres.as_slice(), cache[fetched.id()].genres.as_slice()],
+            CaseFoldLocale::Unicode,
+            None,
+        );
+        assert_eq!(combined, ["indie", "rock"]);
+    }
+}
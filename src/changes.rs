@@ -0,0 +1,50 @@
+//! A manifest of exactly which files a run modified, for incremental backup/sync tools.
+
+use crate::json;
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ChangeKind records whether the genre tag was newly added to a file or replaced an existing one.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Add,
+    Replace,
+}
+
+/// Change is a single entry in the manifest.
+/// `timestamp` is in seconds since the Unix epoch.
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub path: PathBuf,
+    pub old_hash: String,
+    pub new_hash: String,
+    pub timestamp: u64,
+    pub kind: ChangeKind,
+}
+
+/// hash_file returns the hex-encoded SHA-256 of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// unix_timestamp returns the current time in seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// write_manifest atomically writes `changes` as JSON to `path`.
+pub fn write_manifest(path: &Path, changes: &[Change]) -> Result<()> {
+    json::write_atomic(path, changes)
+}
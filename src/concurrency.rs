@@ -0,0 +1,61 @@
+//! Probing how many concurrent Spotify requests the account's rate limit allows, and remembering the result
+//! per client ID so later runs don't have to probe again.
+
+use crate::{api, json};
+use anyhow::Result;
+use futures::future::join_all;
+use log::debug;
+use rspotify::{ClientCredsSpotify, ClientError, http::HttpError, model::TrackId};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// KnownConcurrency is the probed concurrency of each client ID.
+pub type KnownConcurrency = HashMap<String, usize>;
+
+/// PROBE_LEVELS are the concurrency levels tried by [probe], in order.
+const PROBE_LEVELS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+
+/// load reads the known concurrency file at `path`, or returns an empty one if it doesn't exist yet.
+pub fn load(path: &Path) -> Result<KnownConcurrency> {
+    json::read_or_default(path)
+}
+
+/// save atomically writes `known` as JSON to `path`.
+pub fn save(path: &Path, known: &KnownConcurrency) -> Result<()> {
+    json::write_atomic(path, known)
+}
+
+/// is_rate_limited returns whether `e` is Spotify answering 429 Too Many Requests.
+pub fn is_rate_limited(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Http(e) if matches!(**e, HttpError::StatusCode(ref response) if response.status().as_u16() == 429)
+    )
+}
+
+/// probe sends bursts of concurrent track lookups for `sample` at each of [PROBE_LEVELS] and returns the highest
+/// level before the first one that got rate limited (or the highest level if none were).
+/// Errors other than rate limiting are returned as-is.
+pub async fn probe(spotify: &ClientCredsSpotify, sample: &[TrackId<'static>]) -> Result<usize> {
+    let mut sustainable = PROBE_LEVELS[0];
+    for level in PROBE_LEVELS {
+        let results = join_all((0..level).map(|_| api::fetch_tracks(spotify, sample))).await;
+        let mut rate_limited = false;
+        for result in results {
+            match result {
+                Ok(_) => {}
+                Err(e) if is_rate_limited(&e) => rate_limited = true,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        debug!("Concurrency probe at {level}: rate limited: {rate_limited}");
+        if rate_limited {
+            break;
+        }
+        sustainable = level;
+        // Give the rate limit window a moment so one level's burst doesn't count against the next
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(sustainable)
+}
@@ -0,0 +1,151 @@
+//! Picking which of several files matched to the same track to keep, and reporting or quarantining the rest.
+
+use crate::scan::PathsByTrackId;
+use anyhow::{Result, anyhow};
+use ffmpeg_next::format;
+use log::warn;
+use rspotify::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Duplicate is a track matched to more than one file: the file kept for tagging and the redundant ones.
+#[derive(Serialize, Debug)]
+pub struct Duplicate {
+    pub track_id: String,
+    pub kept: PathBuf,
+    pub redundant: Vec<PathBuf>,
+}
+
+/// bit_rate returns the overall bit rate of the song at `path` as reported by ffmpeg, or 0 if it can't be read.
+fn bit_rate(path: &Path) -> i64 {
+    format::input(path).map_or(0, |ictx| ictx.bit_rate())
+}
+
+/// resolve_duplicates picks the file to keep for each track in `paths_by_track_id` that's matched to more than one
+/// file: the one with the highest bit rate, ties going to the first path in sort order. Returned sorted by kept path.
+pub fn resolve_duplicates(paths_by_track_id: &PathsByTrackId) -> Vec<Duplicate> {
+    let mut resolved: Vec<Duplicate> = paths_by_track_id
+        .iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(track, paths)| {
+            let mut paths: Vec<(i64, PathBuf)> = paths.iter().map(|path| (bit_rate(path), path.clone())).collect();
+            paths.sort_by(|(a_rate, a_path), (b_rate, b_path)| b_rate.cmp(a_rate).then(a_path.cmp(b_path)));
+            paths.dedup_by(|(_, a_path), (_, b_path)| a_path == b_path);
+            let mut paths = paths.into_iter().map(|(_, path)| path);
+            Duplicate {
+                track_id: track.id().to_owned(),
+                kept: paths.next().unwrap_or_default(),
+                redundant: paths.collect(),
+            }
+        })
+        .collect();
+    resolved.sort_by(|a, b| a.kept.cmp(&b.kept));
+    resolved
+}
+
+/// quarantine moves every redundant file in `duplicates` into `quarantine_dir`, keeping its path relative to
+/// `base_path` so files with the same name don't collide. Nothing is ever deleted or overwritten: a file whose
+/// destination already exists is left where it is.
+/// Returns how many files were moved.
+pub fn quarantine(duplicates: &[Duplicate], base_path: &Path, quarantine_dir: &Path) -> Result<usize> {
+    let mut moved = 0;
+    for path in duplicates.iter().flat_map(|duplicate| &duplicate.redundant) {
+        let relative_path = path
+            .strip_prefix(base_path)
+            .ok()
+            .or_else(|| path.file_name().map(Path::new))
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+        let destination = quarantine_dir.join(relative_path);
+        if destination.exists() {
+            warn!("{} already exists, leaving {} where it is", destination.display(), path.display());
+            continue;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::rename(path, &destination) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                fs::copy(path, &destination)?;
+                fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rspotify::model::TrackId;
+    use std::collections::HashMap;
+
+    fn track(n: u8) -> TrackId<'static> {
+        TrackId::from_id(format!("{n:022}")).unwrap()
+    }
+
+    #[test]
+    fn highest_bit_rate_wins_and_ties_go_to_the_first_path() {
+        ffmpeg_next::init().unwrap();
+        let folder = tempfile::tempdir().unwrap();
+        let mp3 = folder.path().join("z.mp3");
+        fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/container.mp3"), &mp3).unwrap();
+        // Files ffmpeg can't read count as a bit rate of 0
+        let (unreadable_a, unreadable_b) = (folder.path().join("a.mp3"), folder.path().join("b.mp3"));
+        fs::write(&unreadable_a, b"not audio").unwrap();
+        fs::write(&unreadable_b, b"not audio").unwrap();
+        let paths_by_track_id = HashMap::from([
+            (track(1), vec![unreadable_a.clone(), mp3.clone()]),
+            (track(2), vec![unreadable_b.clone(), unreadable_a.clone()]),
+            (track(3), vec![mp3.clone()]),
+        ]);
+
+        let duplicates = resolve_duplicates(&paths_by_track_id);
+
+        let resolved: Vec<(&str, &Path, &[PathBuf])> = duplicates
+            .iter()
+            .map(|duplicate| (duplicate.track_id.as_str(), duplicate.kept.as_path(), duplicate.redundant.as_slice()))
+            .collect();
+        assert_eq!(
+            resolved,
+            [
+                (track(2).id(), unreadable_a.as_path(), &[unreadable_b][..]),
+                (track(1).id(), mp3.as_path(), &[unreadable_a.clone()][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn quarantine_never_overwrites_an_existing_file() {
+        let library = tempfile::tempdir().unwrap();
+        let quarantine_dir = tempfile::tempdir().unwrap();
+        let (kept, redundant, other) = (
+            library.path().join("Album/01.ogg"),
+            library.path().join("Album/01 (1).ogg"),
+            library.path().join("Album/02 (1).ogg"),
+        );
+        fs::create_dir_all(library.path().join("Album")).unwrap();
+        fs::create_dir_all(quarantine_dir.path().join("Album")).unwrap();
+        for path in [&kept, &redundant, &other] {
+            fs::write(path, b"library").unwrap();
+        }
+        fs::write(quarantine_dir.path().join("Album/01 (1).ogg"), b"quarantined earlier").unwrap();
+        let duplicates = [Duplicate {
+            track_id: track(1).id().to_owned(),
+            kept,
+            redundant: vec![redundant.clone(), other.clone()],
+        }];
+
+        let moved = quarantine(&duplicates, library.path(), quarantine_dir.path()).unwrap();
+
+        assert_eq!(moved, 1);
+        assert_eq!(fs::read(&redundant).unwrap(), b"library");
+        assert_eq!(fs::read(quarantine_dir.path().join("Album/01 (1).ogg")).unwrap(), b"quarantined earlier");
+        assert!(!other.exists());
+        assert_eq!(fs::read(quarantine_dir.path().join("Album/02 (1).ogg")).unwrap(), b"library");
+    }
+}
@@ -0,0 +1,37 @@
+//! A rough estimate of how many Spotify requests fetching genres will make and how long it will take,
+//! for deciding whether to go ahead with a large run.
+
+/// ARTISTS_PER_TRACK is the assumed average number of artists per track, since they aren't known until the tracks
+/// have been fetched.
+const ARTISTS_PER_TRACK: f64 = 1.3;
+
+/// SECS_PER_REQUEST is the assumed round trip time of one Spotify request.
+const SECS_PER_REQUEST: f64 = 0.5;
+
+/// Estimate is the result of [estimate_fetch].
+pub struct Estimate {
+    pub track_requests: usize,
+    pub artist_requests: usize,
+    pub secs: f64,
+}
+
+/// estimate_fetch estimates the requests and time needed to fetch tracks split into chunks of the sizes in
+/// `track_chunks`, with up to `chunk_size` tracks or artists per request and `concurrency` chunks in flight at once.
+/// Time spent waiting out rate limits isn't included.
+pub fn estimate_fetch(track_chunks: &[usize], chunk_size: usize, concurrency: usize) -> Estimate {
+    let mut artist_requests = 0;
+    let mut chunk_secs = Vec::with_capacity(track_chunks.len());
+    for &chunk_len in track_chunks {
+        let chunk_artist_requests = (chunk_len as f64 * ARTISTS_PER_TRACK / chunk_size as f64).ceil() as usize;
+        artist_requests += chunk_artist_requests;
+        chunk_secs.push((1 + chunk_artist_requests) as f64 * SECS_PER_REQUEST);
+    }
+    // Chunks run `concurrency` at a time, but never faster than the slowest single chunk
+    let secs = (chunk_secs.iter().sum::<f64>() / concurrency.max(1) as f64)
+        .max(chunk_secs.iter().copied().fold(0.0, f64::max));
+    Estimate {
+        track_requests: track_chunks.len(),
+        artist_requests,
+        secs,
+    }
+}
@@ -0,0 +1,93 @@
+//! Transforming each track's genres with a user-supplied external command (`--genre-filter-cmd`).
+//!
+//! The command is run through `sh -c` once per track. It receives the track's genres as a JSON array of strings
+//! on stdin (e.g. `["indie rock","shoegaze"]`) and the track's Spotify ID in the `ZOTIFY_TRACK_ID` environment
+//! variable, and must print the transformed genres as a JSON array of strings on stdout and exit with status 0.
+//! Anything else, or taking longer than the timeout, leaves the track's genres unchanged.
+
+use anyhow::{Result, anyhow, bail};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// run_filter runs `cmd` on `genres` for the track `track_id` as described in the module docs,
+/// killing it if it hasn't exited after `timeout`.
+pub fn run_filter(cmd: &str, track_id: &str, genres: &[String], timeout: Duration) -> Result<Vec<String>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("ZOTIFY_TRACK_ID", track_id)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let input = serde_json::to_vec(genres)?;
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Couldn't open stdin"))?;
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let mut stdout = child.stdout.take().ok_or_else(|| anyhow!("Couldn't open stdout"))?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            bail!("Timed out after {timeout:?}");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // A command that exits without reading its input makes the write fail, which doesn't matter by itself
+    let _ = writer.join();
+    let output = reader.join().map_err(|_| anyhow!("Reading stdout panicked"))??;
+    if !status.success() {
+        bail!("Exited with {status}");
+    }
+
+    Ok(serde_json::from_slice(&output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK_ID: &str = "0000000000000000000001";
+
+    fn run(cmd: &str, timeout: Duration) -> Result<Vec<String>> {
+        run_filter(cmd, TRACK_ID, &["indie rock".to_owned(), "shoegaze".to_owned()], timeout)
+    }
+
+    #[test]
+    fn genres_go_in_as_json_and_come_back_as_json() {
+        assert_eq!(run("tr a-z A-Z", Duration::from_secs(5)).unwrap(), ["INDIE ROCK", "SHOEGAZE"]);
+        let echoed = run(r#"cat >/dev/null; printf '["%s"]' "$ZOTIFY_TRACK_ID""#, Duration::from_secs(5)).unwrap();
+        assert_eq!(echoed, [TRACK_ID]);
+    }
+
+    #[test]
+    fn non_zero_exit_is_an_error() {
+        let e = run(r#"cat; exit 3"#, Duration::from_secs(5)).unwrap_err();
+        assert!(e.to_string().contains("Exited"), "{e}");
+    }
+
+    #[test]
+    fn output_that_isnt_a_json_array_of_strings_is_an_error() {
+        assert!(run("echo not json", Duration::from_secs(5)).is_err());
+        assert!(run("echo '[1, 2]'", Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn command_is_killed_after_the_timeout() {
+        let started = Instant::now();
+        let e = run("sleep 10", Duration::from_millis(100)).unwrap_err();
+        assert!(e.to_string().contains("Timed out"), "{e}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,275 @@
+//! Matching a flat directory of loose audio files (no artist/album folders, no `.song_ids`) to Spotify tracks,
+//! by a Spotify track ID embedded in their tags or, failing that, by searching for their title and artist.
+
+use crate::api;
+use crate::mount::MountGuard;
+use crate::scan::PathsByTrackId;
+use anyhow::Result;
+use ffmpeg_next::format;
+use ffmpeg_next::media;
+use log::{info, warn};
+use rspotify::{
+    ClientCredsSpotify,
+    model::{FullTrack, SearchResult, SearchType, TrackId},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// MIN_SEARCH_CONFIDENCE is the lowest [search_confidence] a search result needs to be used. It's above what a title
+/// match alone scores, so a result is never used without at least a partial artist match.
+const MIN_SEARCH_CONFIDENCE: f64 = 0.7;
+
+/// SEARCH_LIMIT is how many search results are compared against a file's tags.
+const SEARCH_LIMIT: u32 = 5;
+
+/// ID_TAGS are the tag keys (compared case-insensitively) that hold a bare track ID, URI, or URL.
+const ID_TAGS: [&str; 3] = ["spotify_track_id", "spotify_id", "spotify"];
+
+/// MatchMethod is how a loose file was matched to a track.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMethod {
+    EmbeddedId,
+    Search,
+}
+
+/// FlatMatch is the outcome of matching one loose file: the track it was matched to, if any, how,
+/// and how confident the match is from 0 to 1 (always 1 for embedded IDs).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlatMatch {
+    pub path: PathBuf,
+    pub track_id: Option<String>,
+    pub method: Option<MatchMethod>,
+    pub confidence: f64,
+}
+
+/// FlatScan is the result of [scan_flat_dir]: every matched file keyed by its [TrackId], and the match
+/// outcome of every file, matched or not.
+pub struct FlatScan {
+    pub paths_by_track_id: PathsByTrackId,
+    pub matches: Vec<FlatMatch>,
+}
+
+/// parse_track_id extracts a [TrackId] from `value`, which may be a bare ID, a `spotify:track:` URI,
+/// or an `open.spotify.com/track/` URL, possibly surrounded by other text.
+pub fn parse_track_id(value: &str) -> Option<TrackId<'static>> {
+    let id = ["spotify:track:", "open.spotify.com/track/"]
+        .iter()
+        .find_map(|prefix| value.split_once(prefix).map(|(_, rest)| rest))
+        .unwrap_or(value.trim());
+    let id: String = id.chars().take_while(char::is_ascii_alphanumeric).collect();
+    TrackId::from_id(id).ok()
+}
+
+/// read_tags returns every tag of the song at `path`, from both the container and its best audio stream,
+/// or None if it has no audio stream (e.g. cover art that ffmpeg opens as an image).
+fn read_tags(path: &Path) -> Result<Option<Vec<(String, String)>>> {
+    let ictx = format::input(path)?;
+    let Some(stream) = ictx.streams().best(media::Type::Audio) else {
+        return Ok(None);
+    };
+    let mut tags: Vec<(String, String)> = ictx
+        .metadata()
+        .iter()
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+    tags.extend(stream.metadata().iter().map(|(key, value)| (key.to_owned(), value.to_owned())));
+    Ok(Some(tags))
+}
+
+/// tag returns the first value of the tag `key` (compared case-insensitively) in `tags`.
+fn tag<'a>(tags: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(tag_key, _)| tag_key.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.as_str())
+}
+
+/// embedded_track_id looks for a Spotify track ID in `tags`: first in the [ID_TAGS], then as a Spotify URI or URL
+/// anywhere in any tag (e.g. a comment).
+fn embedded_track_id(tags: &[(String, String)]) -> Option<TrackId<'static>> {
+    ID_TAGS
+        .iter()
+        .filter_map(|key| tag(tags, key))
+        .find_map(parse_track_id)
+        .or_else(|| {
+            tags.iter()
+                .filter(|(_, value)| value.contains("spotify:track:") || value.contains("open.spotify.com/track/"))
+                .find_map(|(_, value)| parse_track_id(value))
+        })
+}
+
+/// normalize lowercases `s` and collapses its whitespace, for comparing titles and artist names.
+fn normalize(s: &str) -> String {
+    s.to_lowercase().split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// search_confidence scores how well `track` matches a file tagged `title` by `artist`, from 0 to 1.
+/// An exact title match is worth 0.6 and a partial one 0.3; an exact match with any of the track's artists
+/// is worth 0.4 and a partial one (e.g. "A feat. B") 0.2.
+fn search_confidence(title: &str, artist: Option<&str>, track: &FullTrack) -> f64 {
+    let (title, track_title) = (normalize(title), normalize(&track.name));
+    let title_score = if title == track_title {
+        0.6
+    } else if track_title.contains(&title) || title.contains(&track_title) {
+        0.3
+    } else {
+        0.0
+    };
+    let artist_score = match artist.map(normalize) {
+        Some(artist) if track.artists.iter().any(|a| normalize(&a.name) == artist) => 0.4,
+        Some(artist) if track.artists.iter().any(|a| artist.contains(&normalize(&a.name))) => 0.2,
+        _ => 0.0,
+    };
+    title_score + artist_score
+}
+
+/// search_track searches Spotify for the file tagged with `tags` at `path` (falling back to its file name for the
+/// title) and returns the best result with its [search_confidence], if any result reaches [MIN_SEARCH_CONFIDENCE].
+/// The search is retried up to `retries` times through [api::with_retries].
+async fn search_track(
+    spotify: &ClientCredsSpotify,
+    path: &Path,
+    tags: &[(String, String)],
+    retries: u32,
+) -> Result<Option<(TrackId<'static>, f64)>> {
+    let Some(title) = tag(tags, "title")
+        .map(str::to_owned)
+        .or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+    else {
+        return Ok(None);
+    };
+    let artist = tag(tags, "artist");
+    let query = match artist {
+        Some(artist) => format!("track:{title} artist:{artist}"),
+        None => title.clone(),
+    };
+    let SearchResult::Tracks(page) =
+        api::with_retries(spotify, retries, || {
+            spotify.search(&query, SearchType::Track, None, None, Some(SEARCH_LIMIT), None)
+        })
+        .await?
+    else {
+        return Ok(None);
+    };
+    Ok(page
+        .items
+        .iter()
+        .filter_map(|track| Some((track.id.clone()?, search_confidence(&title, artist, track))))
+        .filter(|(_, confidence)| *confidence >= MIN_SEARCH_CONFIDENCE)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b)))
+}
+
+/// scan_flat_dir matches every file directly inside `dir` to a Spotify track, by its embedded ID if it has one,
+/// otherwise by searching `spotify` for its title and artist tags.
+/// Reads that fail while the mount is down are retried through `mount`, and searches up to `retries` times.
+pub async fn scan_flat_dir(
+    spotify: &ClientCredsSpotify,
+    dir: &Path,
+    mount: &MountGuard,
+    retries: u32,
+) -> Result<FlatScan> {
+    let mut paths: Vec<PathBuf> = mount
+        .retry("Reading the flat directory", || fs::read_dir(dir))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+
+    let mut paths_by_track_id: PathsByTrackId = HashMap::new();
+    let mut matches = Vec::new();
+    for path in paths {
+        let tags = match mount.retry("Reading tags", || read_tags(&path)) {
+            Ok(Some(tags)) => tags,
+            Ok(None) => {
+                info!("Skipping {}: it has no audio stream", path.display());
+                continue;
+            }
+            Err(e) => {
+                warn!("Couldn't read the tags of {}, skipping it: {e}", path.display());
+                continue;
+            }
+        };
+        let matched = match embedded_track_id(&tags) {
+            Some(track) => Some((track, MatchMethod::EmbeddedId, 1.0)),
+            None => match search_track(spotify, &path, &tags, retries).await {
+                Ok(found) => found.map(|(track, confidence)| (track, MatchMethod::Search, confidence)),
+                Err(e) => {
+                    warn!("Couldn't search Spotify for {}: {e}", path.display());
+                    None
+                }
+            },
+        };
+        match matched {
+            Some((track, method, confidence)) => {
+                info!("Matched {} to {} by {method:?} with confidence {confidence:.2}", path.display(), track.id());
+                matches.push(FlatMatch {
+                    path: path.clone(),
+                    track_id: Some(track.id().to_owned()),
+                    method: Some(method),
+                    confidence,
+                });
+                let paths = paths_by_track_id.entry(track).or_default();
+                if let Some(prev_path) = paths.first() {
+                    warn!("{} matched the same track as {}, tagging both", path.display(), prev_path.display());
+                }
+                paths.push(path);
+            }
+            None => {
+                info!("Couldn't match {}", path.display());
+                matches.push(FlatMatch {
+                    path,
+                    track_id: None,
+                    method: None,
+                    confidence: 0.0,
+                });
+            }
+        }
+    }
+    Ok(FlatScan {
+        paths_by_track_id,
+        matches,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn track(name: &str, artists: &[&str]) -> FullTrack {
+        let artists: Vec<_> = artists.iter().map(|artist| json!({ "external_urls": {}, "name": artist })).collect();
+        serde_json::from_value(json!({
+            "album": { "artists": [], "available_markets": [], "external_urls": {}, "images": [], "name": "Album" },
+            "artists": artists,
+            "available_markets": [],
+            "disc_number": 1,
+            "duration_ms": 1000,
+            "explicit": false,
+            "external_ids": {},
+            "external_urls": {},
+            "is_local": false,
+            "name": name,
+            "popularity": 0,
+            "track_number": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn title_match_alone_isnt_enough() {
+        let found = track("Intro", &["Somebody Else"]);
+        assert!(search_confidence("Intro", Some("The xx"), &found) < MIN_SEARCH_CONFIDENCE);
+        assert!(search_confidence("Intro", None, &found) < MIN_SEARCH_CONFIDENCE);
+    }
+
+    #[test]
+    fn title_and_artist_match_is_used() {
+        let found = track("Intro", &["The xx"]);
+        assert!(search_confidence("intro", Some("The  XX"), &found) >= MIN_SEARCH_CONFIDENCE);
+        assert!(search_confidence("Intro", Some("The xx feat. Someone"), &found) >= MIN_SEARCH_CONFIDENCE);
+    }
+}
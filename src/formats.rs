@@ -0,0 +1,49 @@
+//! The `formats` diagnostic: a histogram of the container/codec combinations across the library.
+
+use crate::scan::PathsByTrackId;
+use anyhow::Result;
+use ffmpeg_next::{format, media};
+use log::error;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// probe_format opens the file at `path` and returns a label for its container and best audio stream's codec,
+/// such as "ogg/opus", or just "mp3" when the two share a name.
+pub fn probe_format(path: &Path) -> Result<String> {
+    let ictx = format::input(path)?;
+    let container = ictx.format().name().to_owned();
+    let codec = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .map(|stream| stream.parameters().id().name().to_owned())
+        .unwrap_or_else(|| "no audio".to_owned());
+    if container == codec {
+        Ok(container)
+    } else {
+        Ok(format!("{container}/{codec}"))
+    }
+}
+
+/// print_formats probes every path in `paths_by_track_id` and prints how many files use each
+/// container/codec combination, most common first. Files that can't be probed are counted as "unreadable".
+pub fn print_formats(paths_by_track_id: &PathsByTrackId) -> Result<()> {
+    ffmpeg_next::init()?;
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for path in paths_by_track_id.values().flatten() {
+        let label = probe_format(path).unwrap_or_else(|e| {
+            error!("Couldn't probe {}: {e}", path.display());
+            "unreadable".to_owned()
+        });
+        *counts.entry(label).or_default() += 1;
+    }
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_label, a_count), (b_label, b_count)| b_count.cmp(a_count).then(a_label.cmp(b_label)));
+    println!("Formats:");
+    for (label, count) in counts {
+        println!("{label}: {count}");
+    }
+
+    Ok(())
+}
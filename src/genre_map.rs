@@ -0,0 +1,135 @@
+//! Mapping Spotify's granular genres onto a curated set with a user-supplied rules file (`--genre-map`).
+//!
+//! Each non-blank line not starting with `#` is a rule of the form `source -> target`. A source matches a genre
+//! exactly, or, prefixed with `~`, anywhere inside it (`~indie -> indie` maps "australian indie rock" to "indie").
+//! An empty target drops the genre. The first matching rule wins, and sources are compared after
+//! [normalization](crate::genres::normalize_genre), so rules needn't match Spotify's spacing or case.
+
+use crate::genres::{self, CaseFoldLocale};
+use anyhow::{Result, bail};
+use std::fs;
+use std::path::Path;
+
+/// Rule is one `source -> target` line of a genre map.
+#[derive(Debug, Clone)]
+struct Rule {
+    source: String,
+    /// Whether `source` matches anywhere inside a genre rather than the whole genre.
+    substring: bool,
+    /// The genre to map to, or None to drop the genre.
+    target: Option<String>,
+}
+
+/// GenreMap is the parsed rules of a `--genre-map` file, in file order.
+#[derive(Debug, Clone, Default)]
+pub struct GenreMap {
+    rules: Vec<Rule>,
+}
+
+/// load reads and parses the genre map at `path`, normalizing its sources and targets with `locale`.
+pub fn load(path: &Path, locale: CaseFoldLocale) -> Result<GenreMap> {
+    let mut rules = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((source, target)) = line.split_once("->") else {
+            bail!("{}:{}: expected `source -> target`, got {line:?}", path.display(), i + 1);
+        };
+        let (source, substring) = match source.trim().strip_prefix('~') {
+            Some(source) => (source, true),
+            None => (source, false),
+        };
+        let source = genres::normalize_genre(source, locale);
+        if source.is_empty() {
+            bail!("{}:{}: the source genre is empty", path.display(), i + 1);
+        }
+        let target = genres::normalize_genre(target, locale);
+        rules.push(Rule {
+            source,
+            substring,
+            target: (!target.is_empty()).then_some(target),
+        });
+    }
+    Ok(GenreMap { rules })
+}
+
+impl GenreMap {
+    /// apply maps each of `genres` through the first rule matching it. Genres no rule matches are kept as they are,
+    /// or dropped if `drop_unmapped` is set. The result may have duplicates, e.g. when two genres map to the same one.
+    pub fn apply(&self, genres: &[String], drop_unmapped: bool) -> Vec<String> {
+        genres
+            .iter()
+            .filter_map(|genre| {
+                let rule = self.rules.iter().find(|rule| match rule.substring {
+                    true => genre.contains(&rule.source),
+                    false => *genre == rule.source,
+                });
+                match rule {
+                    Some(rule) => rule.target.clone(),
+                    None if drop_unmapped => None,
+                    None => Some(genre.clone()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// genre_map parses the rules in `rules` as if they were a `--genre-map` file.
+    fn genre_map(rules: &str) -> GenreMap {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("genre-map.txt");
+        fs::write(&path, rules).unwrap();
+        load(&path, CaseFoldLocale::Unicode).unwrap()
+    }
+
+    fn strings(genres: &[&str]) -> Vec<String> {
+        genres.iter().map(|genre| genre.to_string()).collect()
+    }
+
+    #[test]
+    fn substring_rule_matches_anywhere_in_a_genre() {
+        let map = genre_map("~Indie -> indie\n");
+        let mapped = map.apply(&strings(&["australian indie rock", "indietronica", "rock"]), false);
+        assert_eq!(mapped, strings(&["indie", "indie", "rock"]));
+    }
+
+    #[test]
+    fn exact_rule_only_matches_the_whole_genre() {
+        let map = genre_map("indie -> alternative\n");
+        assert_eq!(map.apply(&strings(&["indie", "indie rock"]), false), strings(&["alternative", "indie rock"]));
+    }
+
+    #[test]
+    fn empty_target_drops_the_genre() {
+        let map = genre_map("# Too vague to be useful\nmellow gold ->\n");
+        assert_eq!(map.apply(&strings(&["mellow gold", "soft rock"]), false), strings(&["soft rock"]));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let map = genre_map("~rock -> rock\nindie rock -> indie\n");
+        assert_eq!(map.apply(&strings(&["indie rock"]), false), strings(&["rock"]));
+    }
+
+    #[test]
+    fn drop_unmapped_keeps_only_mapped_genres() {
+        let map = genre_map("~jazz -> jazz\n");
+        let genres = strings(&["bebop", "cool jazz", "hard bop"]);
+        assert_eq!(map.apply(&genres, true), strings(&["jazz"]));
+        assert_eq!(map.apply(&genres, false), strings(&["bebop", "jazz", "hard bop"]));
+    }
+
+    #[test]
+    fn line_without_an_arrow_is_an_error() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("genre-map.txt");
+        fs::write(&path, "rock\n").unwrap();
+        assert!(load(&path, CaseFoldLocale::Unicode).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! Turning the genres of a track's artists into the genres written to the track.
+
+use crate::m3u::TrackInfo;
+use crate::scan::PathsByTrackId;
+use clap::ValueEnum;
+use log::info;
+use rspotify::model::{AlbumId, TrackId};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// CombineMode is how the genre lists of a track's artists are combined into the track's genres.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum CombineMode {
+    /// Every genre of any of the track's artists.
+    #[default]
+    Union,
+    /// Only the genres shared by all of the track's artists.
+    Intersection,
+}
+
+/// combine_artist_genres combines the genre lists in `artist_genres`, one per artist, according to `mode`.
+/// Genres are [normalized](normalize_genre) with `locale` first, so differently formatted copies of the same genre
+/// from different artists are combined (and counted) as one.
+/// Each artist contributes at most its first `max_per_artist` genres, keeping the order Spotify listed them in.
+/// The result has no duplicates but isn't sorted; that happens in [cap_genres] once all tracks are resolved.
+/// With a single artist, both modes return that artist's genres.
+pub fn combine_artist_genres(
+    mode: CombineMode,
+    artist_genres: &[&[String]],
+    locale: CaseFoldLocale,
+    max_per_artist: Option<usize>,
+) -> Vec<String> {
+    let artist_genres: Vec<Vec<String>> = artist_genres
+        .iter()
+        .map(|genres| {
+            let mut genres = normalize_genres_in_order(genres, locale);
+            genres.truncate(max_per_artist.unwrap_or(usize::MAX));
+            genres
+        })
+        .collect();
+    let mut combined: Vec<String> = match mode {
+        CombineMode::Union => artist_genres.concat(),
+        CombineMode::Intersection => match artist_genres.split_first() {
+            Some((first, rest)) => first
+                .iter()
+                .filter(|genre| rest.iter().all(|genres| genres.contains(*genre)))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        },
+    };
+    let mut seen = HashSet::new();
+    combined.retain(|genre| seen.insert(genre.clone()));
+    combined
+}
+
+/// album_genres_by_track returns the album genres from `genres_by_album` that each track in `album_by_track` is
+/// tagged with under `--prefer-album-genres`. Tracks whose album has no genres (as most don't on Spotify) or
+/// wasn't returned are left out, so they fall back to their artists' genres.
+pub fn album_genres_by_track(
+    album_by_track: &HashMap<TrackId<'static>, AlbumId<'static>>,
+    genres_by_album: &HashMap<AlbumId<'static>, Vec<String>>,
+) -> HashMap<TrackId<'static>, Vec<String>> {
+    album_by_track
+        .iter()
+        .filter_map(|(track, album)| {
+            let genres = genres_by_album.get(album).filter(|genres| !genres.is_empty())?;
+            Some((track.clone(), genres.clone()))
+        })
+        .collect()
+}
+
+/// CaseFoldLocale is the locale whose case rules are used when lowercasing genres.
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+pub enum CaseFoldLocale {
+    /// Unicode's default lowercase mapping, which handles non-ASCII letters (e.g. "Ÿ" becomes "ÿ").
+    #[default]
+    Unicode,
+    /// Turkish/Azerbaijani rules: dotless "I" lowercases to "ı" and dotted "İ" to "i".
+    Turkic,
+}
+
+/// normalize_genre trims `genre`, collapses runs of whitespace to a single space, and lowercases it using
+/// the case rules of `locale`. Lowercasing is Unicode-aware, not ASCII-only.
+pub fn normalize_genre(genre: &str, locale: CaseFoldLocale) -> String {
+    let collapsed = genre.split_whitespace().collect::<Vec<_>>().join(" ");
+    match locale {
+        CaseFoldLocale::Unicode => collapsed.to_lowercase(),
+        CaseFoldLocale::Turkic => collapsed
+            .chars()
+            .map(|c| match c {
+                'I' => 'ı'.to_string(),
+                'İ' => 'i'.to_string(),
+                c => c.to_lowercase().to_string(),
+            })
+            .collect(),
+    }
+}
+
+/// normalize_genres_in_order normalizes each of `genres` with [normalize_genre] and drops any that normalize to one
+/// already seen, keeping the original order (which `--max-genres-per-artist` and `--max-genres` go by).
+pub fn normalize_genres_in_order(genres: &[String], locale: CaseFoldLocale) -> Vec<String> {
+    let mut seen = HashSet::new();
+    genres
+        .iter()
+        .map(|genre| normalize_genre(genre, locale))
+        .filter(|genre| seen.insert(genre.clone()))
+        .collect()
+}
+
+/// genre_counts counts how many tracks have each genre across `genres`, one list per track,
+/// most common first (ties broken alphabetically).
+pub fn genre_counts<'a>(genres: impl IntoIterator<Item = &'a Vec<String>>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for track_genres in genres {
+        for genre in track_genres {
+            *counts.entry(genre.as_str()).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<_> = counts
+        .into_iter()
+        .map(|(genre, count)| (genre.to_owned(), count))
+        .collect();
+    counts.sort_by(|(a_genre, a_count), (b_genre, b_count)| b_count.cmp(a_count).then(a_genre.cmp(b_genre)));
+    counts
+}
+
+/// GenreJoin is how a track's genres are written into its genre tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GenreJoin {
+    /// A single tag with the genres joined by this delimiter.
+    Delimiter(String),
+    /// One tag per genre, for when no delimiter is safe.
+    MultiValue,
+}
+
+/// DELIMITER_CANDIDATES are the delimiters tried by [detect_delimiter], most preferred first.
+const DELIMITER_CANDIDATES: [char; 8] = [',', ';', '|', '/', '+', '~', '^', '\\'];
+
+/// detect_delimiter picks the first of [DELIMITER_CANDIDATES] that appears in none of `genres`, one list per track,
+/// so splitting a written tag on it gives back exactly the genres that were joined.
+/// Returns [GenreJoin::MultiValue] if every candidate appears in some genre.
+pub fn detect_delimiter<'a>(genres: impl IntoIterator<Item = &'a Vec<String>> + Clone) -> GenreJoin {
+    DELIMITER_CANDIDATES
+        .iter()
+        .find(|delimiter| {
+            !genres
+                .clone()
+                .into_iter()
+                .flatten()
+                .any(|genre| genre.contains(**delimiter))
+        })
+        .map_or(GenreJoin::MultiValue, |delimiter| GenreJoin::Delimiter(delimiter.to_string()))
+}
+
+/// GenreScope is which set of genres each track is tagged with.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GenreScope {
+    /// The genres of the track's own artists.
+    #[default]
+    Track,
+    /// The genres of every track in the same album folder, so the whole album is tagged alike.
+    Album,
+    /// The track's own genres, or the album's if its artists have none.
+    TrackThenAlbum,
+}
+
+/// COMPILATION_MIN_ARTISTS is how many distinct primary artists an album folder needs before
+/// [compilation_albums] treats it as a compilation even if Spotify doesn't say so.
+const COMPILATION_MIN_ARTISTS: usize = 4;
+
+/// compilation_albums returns the album folders in `paths_by_track_id` that look like compilations, going by the
+/// metadata in `track_info`. A folder is a compilation if Spotify gives any of its tracks the album type
+/// "compilation" or the album artist "Various Artists", or if its tracks have at least [COMPILATION_MIN_ARTISTS]
+/// distinct primary (first-listed) artists.
+pub fn compilation_albums<'a>(
+    paths_by_track_id: &'a PathsByTrackId,
+    track_info: &HashMap<TrackId<'static>, TrackInfo>,
+) -> HashSet<&'a Path> {
+    let mut primary_artists_by_album: HashMap<&Path, HashSet<&str>> = HashMap::new();
+    let mut compilations = HashSet::new();
+    let paths = paths_by_track_id.iter().flat_map(|(track, paths)| paths.iter().map(move |path| (track, path)));
+    for (track, path) in paths {
+        let (Some(album), Some(info)) = (path.parent(), track_info.get(track)) else {
+            continue;
+        };
+        if info.album_type.as_deref() == Some("compilation")
+            || info.album_artists.iter().any(|artist| artist.eq_ignore_ascii_case("various artists"))
+        {
+            compilations.insert(album);
+        }
+        if let Some(artist) = info.artists.first() {
+            primary_artists_by_album.entry(album).or_default().insert(artist);
+        }
+    }
+    compilations.extend(
+        primary_artists_by_album
+            .into_iter()
+            .filter(|(_, artists)| artists.len() >= COMPILATION_MIN_ARTISTS)
+            .map(|(album, _)| album),
+    );
+    compilations
+}
+
+/// apply_scope replaces the genres in `genres_by_track` according to `scope`, using `paths_by_track_id`
+/// to group tracks into albums by folder. Album genres are the deduplicated union of the genres of every track in
+/// the album, those on the most tracks first (ties alphabetically) so [cap_genres] keeps the album's most typical
+/// ones, and `genres_by_track` should already be normalized.
+/// Tracks in `compilations` always keep their own genres, since the union of a compilation's genres is
+/// rarely meaningful for any one of its tracks. A track matched to files in several albums gets the union of
+/// those albums' genres.
+pub fn apply_scope(
+    scope: GenreScope,
+    genres_by_track: &mut HashMap<TrackId<'static>, Vec<String>>,
+    paths_by_track_id: &PathsByTrackId,
+    compilations: &HashSet<&Path>,
+) {
+    if scope == GenreScope::Track {
+        return;
+    }
+    for album in compilations {
+        info!("{} looks like a compilation, so its tracks keep their own genres", album.display());
+    }
+    let albums_of = |track: &TrackId<'static>| {
+        paths_by_track_id
+            .get(track)
+            .into_iter()
+            .flatten()
+            .filter_map(|path| path.parent())
+            .filter(|album| !compilations.contains(album))
+    };
+
+    let mut track_genres_by_album: HashMap<&Path, Vec<&Vec<String>>> = HashMap::new();
+    for (track, genres) in genres_by_track.iter() {
+        for album in albums_of(track) {
+            track_genres_by_album.entry(album).or_default().push(genres);
+        }
+    }
+    let genres_by_album: HashMap<&Path, Vec<String>> = track_genres_by_album
+        .into_iter()
+        .map(|(album, track_genres)| (album, genre_counts(track_genres).into_iter().map(|(genre, _)| genre).collect()))
+        .collect();
+
+    for (track, genres) in genres_by_track.iter_mut() {
+        if scope == GenreScope::TrackThenAlbum && !genres.is_empty() {
+            continue;
+        }
+        let mut album_genres: Vec<String> = albums_of(track)
+            .filter_map(|album| genres_by_album.get(album))
+            .flatten()
+            .cloned()
+            .collect();
+        if album_genres.is_empty() {
+            continue;
+        }
+        let mut seen = HashSet::new();
+        album_genres.retain(|genre| seen.insert(genre.clone()));
+        *genres = album_genres;
+    }
+}
+
+/// cap_genres cuts each track's genres in `genres_by_track` down to its first `max_genres`, then sorts them. Up to
+/// here they're in order of priority: Spotify's order for a track's own genres, most common first for an album's.
+pub fn cap_genres(genres_by_track: &mut HashMap<TrackId<'static>, Vec<String>>, max_genres: Option<usize>) {
+    for genres in genres_by_track.values_mut() {
+        genres.truncate(max_genres.unwrap_or(usize::MAX));
+        genres.sort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn strings(genres: &[&str]) -> Vec<String> {
+        genres.iter().map(|genre| genre.to_string()).collect()
+    }
+
+    #[test]
+    fn union_keeps_every_artists_genres_once() {
+        let (a, b) = (strings(&["rock", "indie rock"]), strings(&["Indie Rock", "shoegaze"]));
+        let combined = combine_artist_genres(CombineMode::Union, &[&a, &b], CaseFoldLocale::Unicode, None);
+        assert_eq!(combined, strings(&["rock", "indie rock", "shoegaze"]));
+    }
+
+    #[test]
+    fn intersection_keeps_only_shared_genres() {
+        let (a, b) = (strings(&["rock", "indie rock", "pop"]), strings(&["pop", "Indie  Rock", "shoegaze"]));
+        let combined = combine_artist_genres(CombineMode::Intersection, &[&a, &b], CaseFoldLocale::Unicode, None);
+        assert_eq!(combined, strings(&["indie rock", "pop"]));
+    }
+
+    #[test]
+    fn intersection_of_disjoint_artists_is_empty() {
+        let (a, b) = (strings(&["rock"]), strings(&["jazz"]));
+        let combined = combine_artist_genres(CombineMode::Intersection, &[&a, &b], CaseFoldLocale::Unicode, None);
+        assert!(combined.is_empty());
+    }
+
+    #[test]
+    fn single_artist_is_the_same_in_both_modes() {
+        let a = strings(&["rock", "pop"]);
+        for mode in [CombineMode::Union, CombineMode::Intersection] {
+            assert_eq!(combine_artist_genres(mode, &[&a], CaseFoldLocale::Unicode, None), a);
+        }
+    }
+
+    #[test]
+    fn normalize_genre_lowercases_non_ascii() {
+        assert_eq!(
+            normalize_genre("  MÚSICA   POPULAR BRASILEIRA ", CaseFoldLocale::Unicode),
+            "música popular brasileira"
+        );
+        assert_eq!(normalize_genre("ŸÉ-YÉ", CaseFoldLocale::Unicode), "ÿé-yé");
+        assert_eq!(normalize_genre("ΕΝΤΕΧΝΟ", CaseFoldLocale::Unicode), "εντεχνο");
+    }
+
+    #[test]
+    fn normalize_genre_follows_turkic_dotted_and_dotless_i() {
+        assert_eq!(normalize_genre("TÜRK HALK MÜZİĞİ", CaseFoldLocale::Turkic), "türk halk müziği");
+        assert_eq!(normalize_genre("ARABESK IŞIK", CaseFoldLocale::Turkic), "arabesk ışık");
+        // Unicode's default mapping keeps the dot above "İ" as a combining character and lowercases "I" to "i"
+        assert_eq!(normalize_genre("MÜZİĞİ IŞIK", CaseFoldLocale::Unicode), "müzi\u{307}ği\u{307} işik");
+    }
+
+    #[test]
+    fn each_artist_is_capped_before_combining() {
+        let prolific: Vec<String> = (1..=10).map(|n| format!("genre {n}")).collect();
+        let other = strings(&["genre 9", "other"]);
+        let artists: [&[String]; 2] = [&prolific, &other];
+        let combine = |mode| combine_artist_genres(mode, &artists, CaseFoldLocale::Unicode, Some(3));
+        // "genre 9" only survives as the other artist's; the prolific artist's copy was already capped away
+        assert_eq!(combine(CombineMode::Union), strings(&["genre 1", "genre 2", "genre 3", "genre 9", "other"]));
+        assert!(combine(CombineMode::Intersection).is_empty());
+    }
+
+    #[test]
+    fn tracks_without_album_genres_fall_back_to_artist_genres() {
+        let album = |n: u8| AlbumId::from_id(format!("{n:022}")).unwrap();
+        let album_by_track = HashMap::from([(track(1), album(1)), (track(2), album(2)), (track(3), album(3))]);
+        // Album 2 has no genres and album 3 wasn't returned
+        let genres_by_album = HashMap::from([(album(1), strings(&["Bossa Nova"])), (album(2), strings(&[]))]);
+        let album_genres = album_genres_by_track(&album_by_track, &genres_by_album);
+        assert_eq!(album_genres, HashMap::from([(track(1), strings(&["Bossa Nova"]))]));
+    }
+
+    fn track(n: u8) -> TrackId<'static> {
+        TrackId::from_id(format!("{n:022}")).unwrap()
+    }
+
+    /// scope_fixture has tracks 1 and 2 in album "a" and track 3 alone in album "b". Track 2 has no genres of its own.
+    fn scope_fixture() -> (HashMap<TrackId<'static>, Vec<String>>, PathsByTrackId) {
+        let genres_by_track = HashMap::from([
+            (track(1), strings(&["rock"])),
+            (track(2), strings(&[])),
+            (track(3), strings(&["jazz"])),
+        ]);
+        let paths_by_track_id = HashMap::from([
+            (track(1), vec![PathBuf::from("a/1.ogg")]),
+            (track(2), vec![PathBuf::from("a/2.ogg")]),
+            (track(3), vec![PathBuf::from("b/3.ogg")]),
+        ]);
+        (genres_by_track, paths_by_track_id)
+    }
+
+    #[test]
+    fn track_scope_leaves_genres_alone() {
+        let (mut genres_by_track, paths_by_track_id) = scope_fixture();
+        apply_scope(GenreScope::Track, &mut genres_by_track, &paths_by_track_id, &HashSet::new());
+        assert_eq!(genres_by_track, scope_fixture().0);
+    }
+
+    #[test]
+    fn album_scope_gives_every_track_the_album_union() {
+        let (mut genres_by_track, paths_by_track_id) = scope_fixture();
+        genres_by_track.insert(track(2), strings(&["indie"]));
+        apply_scope(GenreScope::Album, &mut genres_by_track, &paths_by_track_id, &HashSet::new());
+        assert_eq!(genres_by_track[&track(1)], strings(&["indie", "rock"]));
+        assert_eq!(genres_by_track[&track(2)], strings(&["indie", "rock"]));
+        assert_eq!(genres_by_track[&track(3)], strings(&["jazz"]));
+    }
+
+    #[test]
+    fn track_then_album_scope_only_fills_in_empty_tracks() {
+        let (mut genres_by_track, mut paths_by_track_id) = scope_fixture();
+        genres_by_track.insert(track(4), strings(&["indie"]));
+        paths_by_track_id.insert(track(4), vec![PathBuf::from("a/4.ogg")]);
+        apply_scope(GenreScope::TrackThenAlbum, &mut genres_by_track, &paths_by_track_id, &HashSet::new());
+        assert_eq!(genres_by_track[&track(1)], strings(&["rock"]));
+        assert_eq!(genres_by_track[&track(2)], strings(&["indie", "rock"]));
+        assert_eq!(genres_by_track[&track(4)], strings(&["indie"]));
+    }
+
+    #[test]
+    fn max_genres_caps_the_album_union() {
+        let (mut genres_by_track, paths_by_track_id) = scope_fixture();
+        genres_by_track.insert(track(1), strings(&["rock", "pop"]));
+        genres_by_track.insert(track(2), strings(&["shoegaze", "rock"]));
+        apply_scope(GenreScope::Album, &mut genres_by_track, &paths_by_track_id, &HashSet::new());
+        // The union of album "a" is rock (on both tracks), then pop and shoegaze
+        assert_eq!(genres_by_track[&track(1)], strings(&["rock", "pop", "shoegaze"]));
+        cap_genres(&mut genres_by_track, Some(2));
+        assert_eq!(genres_by_track[&track(1)], strings(&["pop", "rock"]));
+        assert_eq!(genres_by_track[&track(2)], strings(&["pop", "rock"]));
+        assert_eq!(genres_by_track[&track(3)], strings(&["jazz"]));
+    }
+
+    #[test]
+    fn cap_keeps_the_first_genres_then_sorts_them() {
+        let mut genres_by_track = HashMap::from([(track(1), strings(&["shoegaze", "dream pop", "rock"]))]);
+        cap_genres(&mut genres_by_track, Some(2));
+        assert_eq!(genres_by_track[&track(1)], strings(&["dream pop", "shoegaze"]));
+        cap_genres(&mut genres_by_track, None);
+        assert_eq!(genres_by_track[&track(1)], strings(&["dream pop", "shoegaze"]));
+    }
+
+    #[test]
+    fn compilation_tracks_keep_their_own_genres() {
+        let (mut genres_by_track, paths_by_track_id) = scope_fixture();
+        let compilations = HashSet::from([Path::new("a")]);
+        apply_scope(GenreScope::Album, &mut genres_by_track, &paths_by_track_id, &compilations);
+        assert_eq!(genres_by_track, scope_fixture().0);
+    }
+}
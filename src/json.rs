@@ -0,0 +1,26 @@
+//! Helpers for the JSON files the tagger reads and writes alongside the library.
+
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// write_atomic writes `value` as JSON to `path`.
+/// It's written to a sibling temp file first and renamed into place, so readers never observe a partial file.
+pub fn write_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    fs::write(&temp_path, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// read_or_default reads JSON from `path`, or returns `T::default()` if the file doesn't exist yet.
+pub fn read_or_default<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,107 @@
+//! Per-album records of when the tagger last processed each album, for `--only-new`.
+
+use crate::json;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// LastRun maps each album folder to the time (in seconds since the Unix epoch) the tagger last finished with it.
+pub type LastRun = HashMap<PathBuf, u64>;
+
+/// load reads the last-run records at `path`, or returns none if the file doesn't exist yet.
+pub fn load(path: &Path) -> Result<LastRun> {
+    json::read_or_default(path)
+}
+
+/// save atomically writes the last-run records to `path`.
+pub fn save(path: &Path, last_run: &LastRun) -> Result<()> {
+    json::write_atomic(path, last_run)
+}
+
+/// modified_secs returns the modification time of `path` in seconds since the Unix epoch.
+fn modified_secs(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// is_new returns whether the song at `song_path` was modified after its album was last processed.
+/// Songs in albums without a record have never been processed, so they're always new.
+/// (The `.song_ids` modification time isn't a usable fallback: Zotify rewrites it after every download,
+/// so it's always newer than the songs it lists.)
+pub fn is_new(song_path: &Path, last_run: &LastRun) -> Result<bool> {
+    let album_folder = song_path.parent().unwrap_or(Path::new(""));
+    match last_run.get(album_folder) {
+        Some(last_processed) => Ok(modified_secs(song_path)? > *last_processed),
+        None => Ok(true),
+    }
+}
+
+/// record marks the album folders of `scanned_paths` as processed at `timestamp`, but only those whose every scanned
+/// file is in `completed`: an album with a file left out of the run (by `--limit`, a failure, or a crash) isn't done,
+/// so `--only-new` still picks it back up.
+pub fn record<'a>(
+    last_run: &mut LastRun,
+    scanned_paths: impl IntoIterator<Item = &'a PathBuf>,
+    completed: &HashSet<PathBuf>,
+    timestamp: u64,
+) {
+    let mut finished_by_album: HashMap<&Path, bool> = HashMap::new();
+    for song_path in scanned_paths {
+        if let Some(album_folder) = song_path.parent() {
+            *finished_by_album.entry(album_folder).or_insert(true) &= completed.contains(song_path);
+        }
+    }
+    for (album_folder, finished) in finished_by_album {
+        if finished {
+            last_run.insert(album_folder.to_path_buf(), timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{self, PathsByTrackId};
+    use rspotify::model::TrackId;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn album_with_an_unprocessed_file_is_not_recorded() {
+        let scanned = paths(&["Artist/Album/1.ogg", "Artist/Album/2.ogg", "Artist/Other/1.ogg"]);
+        let completed = paths(&["Artist/Album/1.ogg", "Artist/Other/1.ogg"]).into_iter().collect();
+        let mut last_run = LastRun::new();
+        record(&mut last_run, &scanned, &completed, 100);
+        assert_eq!(last_run, LastRun::from([(PathBuf::from("Artist/Other"), 100)]));
+    }
+
+    #[test]
+    fn album_cut_short_by_limit_is_not_recorded() {
+        let track = |n| TrackId::from_id(format!("000000000000000000000{n}")).unwrap();
+        let mut paths_by_track_id = PathsByTrackId::from([
+            (track(1), paths(&["A/1.ogg"])),
+            (track(2), paths(&["A/2.ogg"])),
+            (track(3), paths(&["B/1.ogg"])),
+        ]);
+        let scanned: Vec<PathBuf> = paths_by_track_id.values().flatten().cloned().collect();
+        assert!(scan::limit_files(&mut paths_by_track_id, 1));
+        // Only what --limit left was written
+        let completed: HashSet<PathBuf> = paths_by_track_id.values().flatten().cloned().collect();
+        assert_eq!(completed, HashSet::from([PathBuf::from("A/1.ogg")]));
+        let mut last_run = LastRun::new();
+        record(&mut last_run, &scanned, &completed, 100);
+        assert!(last_run.is_empty());
+    }
+
+    #[test]
+    fn earlier_record_of_an_unfinished_album_is_kept() {
+        let scanned = paths(&["Album/1.ogg", "Album/2.ogg"]);
+        let completed = paths(&["Album/1.ogg"]).into_iter().collect();
+        let mut last_run = LastRun::from([(PathBuf::from("Album"), 50)]);
+        record(&mut last_run, &scanned, &completed, 100);
+        assert_eq!(last_run[Path::new("Album")], 50);
+    }
+}
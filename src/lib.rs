@@ -0,0 +1,28 @@
+// Zotify genre tagger
+// Ari Rios <me@aririos.com>
+// License: MIT
+//!
+//! The library behind the `zotify-genre-tagger` binary, split out so integration tests can reach it.
+
+pub mod api;
+pub mod args;
+pub mod cache;
+pub mod changes;
+pub mod duplicates;
+pub mod concurrency;
+pub mod estimate;
+pub mod filter_cmd;
+pub mod flat;
+pub mod formats;
+pub mod genre_map;
+pub mod genres;
+pub mod json;
+pub mod last_run;
+pub mod m3u;
+pub mod mount;
+pub mod progress;
+pub mod rename;
+pub mod report;
+pub mod scan;
+pub mod sqlite;
+pub mod write;
@@ -0,0 +1,120 @@
+//! Writing an extended M3U playlist of the processed tracks with their resolved genres.
+//!
+//! The playlist starts with `#EXTM3U`, then for each track (sorted by path):
+//! 1. `#EXTINF:<duration in seconds>,<artists> - <title>` (`-1` and the file name if Spotify didn't return the track)
+//! 2. `#EXTGENRE:<genres, comma-separated>`
+//! 3. the track's path
+
+use crate::scan::PathsByTrackId;
+use anyhow::Result;
+use rspotify::model::TrackId;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// TrackInfo is the metadata Spotify returned for a track, kept for output beyond the genre tag itself.
+#[derive(Debug, Clone)]
+pub struct TrackInfo {
+    pub name: String,
+    pub artists: Vec<String>,
+    /// The Spotify IDs of `artists`, for artists Spotify gave an ID.
+    pub artist_ids: Vec<String>,
+    pub album: String,
+    pub track_number: u32,
+    pub disc_number: i32,
+    pub duration_secs: i64,
+    /// The type Spotify gives the track's album, e.g. "album", "single", or "compilation".
+    pub album_type: Option<String>,
+    pub album_artists: Vec<String>,
+}
+
+/// write_playlist writes an extended M3U of every path of every track in `genres_by_track` to `m3u_path`.
+pub fn write_playlist(
+    m3u_path: &Path,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    paths_by_track_id: &PathsByTrackId,
+    track_info: &HashMap<TrackId<'static>, TrackInfo>,
+) -> Result<usize> {
+    let mut entries: Vec<_> = genres_by_track
+        .iter()
+        .filter_map(|(track, genres)| Some((paths_by_track_id.get(track)?, track, genres)))
+        .flat_map(|(paths, track, genres)| paths.iter().map(move |path| (path, track_info.get(track), genres)))
+        .collect();
+    entries.sort_by_key(|(path, _, _)| *path);
+
+    let mut playlist = String::from("#EXTM3U\n");
+    for (path, info, genres) in &entries {
+        match info {
+            Some(info) => writeln!(
+                playlist,
+                "#EXTINF:{},{} - {}",
+                info.duration_secs,
+                info.artists.join(", "),
+                info.name
+            )?,
+            None => writeln!(
+                playlist,
+                "#EXTINF:-1,{}",
+                path.file_stem().unwrap_or_default().to_string_lossy()
+            )?,
+        }
+        writeln!(playlist, "#EXTGENRE:{}", genres.join(","))?;
+        writeln!(playlist, "{}", path.display())?;
+    }
+    fs::write(m3u_path, playlist)?;
+
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn track(n: u8) -> TrackId<'static> {
+        TrackId::from_id(format!("{n:022}")).unwrap()
+    }
+
+    #[test]
+    fn entries_are_extinf_then_extgenre_then_path_sorted_by_path() {
+        let folder = tempfile::tempdir().unwrap();
+        let m3u_path = folder.path().join("tagged.m3u");
+        let genres_by_track = HashMap::from([
+            (track(1), vec!["indie rock".to_owned(), "shoegaze".to_owned()]),
+            (track(2), vec!["jazz".to_owned()]),
+        ]);
+        let paths_by_track_id = HashMap::from([
+            (track(1), vec![PathBuf::from("b/Only Shallow.ogg")]),
+            (track(2), vec![PathBuf::from("a/Unknown.ogg")]),
+        ]);
+        let track_info = HashMap::from([(
+            track(1),
+            TrackInfo {
+                name: "Only Shallow".to_owned(),
+                artists: vec!["my bloody valentine".to_owned(), "Guest".to_owned()],
+                artist_ids: Vec::new(),
+                album: "Loveless".to_owned(),
+                track_number: 1,
+                disc_number: 1,
+                duration_secs: 257,
+                album_type: Some("album".to_owned()),
+                album_artists: vec!["my bloody valentine".to_owned()],
+            },
+        )]);
+
+        let written = write_playlist(&m3u_path, &genres_by_track, &paths_by_track_id, &track_info).unwrap();
+
+        assert_eq!(written, 2);
+        assert_eq!(
+            fs::read_to_string(&m3u_path).unwrap(),
+            "#EXTM3U\n\
+             #EXTINF:-1,Unknown\n\
+             #EXTGENRE:jazz\n\
+             a/Unknown.ogg\n\
+             #EXTINF:257,my bloody valentine, Guest - Only Shallow\n\
+             #EXTGENRE:indie rock,shoegaze\n\
+             b/Only Shallow.ogg\n"
+        );
+    }
+}
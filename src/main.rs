@@ -5,241 +5,192 @@
 //! For when you forgot to enable genre tagging in Zotify.
 #![feature(closure_lifetime_binder)]
 
-use anyhow::Result;
-use dotenvy;
-use ffmpeg_next::{
-    Rational, Stream, codec, encoder,
-    format::{self, context::Input},
-    media,
+use zotify_genre_tagger::{
+    api, args, cache, changes, concurrency, duplicates, estimate, filter_cmd, flat, formats, genre_map, genres, json,
+    last_run, m3u, mount, progress, rename, report, scan, sqlite, write,
 };
+
+use anyhow::{Result, anyhow};
+use args::{Args, Command};
+use changes::{Change, ChangeKind};
+use clap::Parser;
+use flat::MatchMethod;
+use genres::GenreJoin;
+use last_run::LastRun;
+use m3u::TrackInfo;
+use mount::MountGuard;
+use report::{Counters, Failure, Phase, Report};
+use scan::PathsByTrackId;
+use write::WriteOutcome;
+use dotenvy;
 use futures::future::join_all;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, warn};
 use rspotify::{
-    ClientCredsSpotify, Credentials,
-    model::{ArtistId, TrackId},
+    ClientCredsSpotify, Config, Credentials,
+    model::{AlbumId, ArtistId, TrackId},
     prelude::*,
 };
-use std::{env, time::Duration};
-use std::fs::{self, DirEntry};
-use std::io::Error;
-use std::path::PathBuf;
+use std::time::Duration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    thread,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, ScopedJoinHandle},
 };
-use tokio;
-use rand::Rng;
-
-/// ContextOrStream is used to abstract over metadata assigned to a container 
-///  or to a specific stream inside that container.
-enum ContextOrStream<'a> {
-    Context(&'a Input),
-    Stream(&'a Stream<'a>),
+use tokio::{self, sync::Semaphore};
+
+/// StagedWrite is a remuxed temp file waiting to replace its original, which was matched to `track`.
+/// `old_hash` is the hash of the original if a changes manifest was requested.
+struct StagedWrite {
+    track: TrackId<'static>,
+    path: PathBuf,
+    temp_path: PathBuf,
+    old_hash: Option<String>,
+    kind: ChangeKind,
 }
 
-/// insert_song_path will insert a [PathBuf] matching a given [TrackId] into paths_by_track_id.
-/// `id` is the TrackId as a [String].
-/// `song_result_wrapped` is the [Result] of the song file search.
-/// `found_counter`, `dup_counter`, and `error_counter` are references to success, duplicate, and error counters.
-/// `paths_by_track_id` is passed directly.
-/// `album_folder` is the [Result] of the album folder search.
-fn insert_song_path(
-    id: String,
-    song_result_wrapped: &Result<DirEntry, Error>,
-    found_counter: &mut i32,
-    dup_counter: &mut i32,
-    error_counter: &mut i32,
-    paths_by_track_id: Arc<Mutex<HashMap<TrackId, PathBuf>>>,
-    album_folder: &Vec<Result<DirEntry, Error>>,
-) -> Result<()> {
-    trace!(
-        "insert_song_path(id: {id:?}, song_result_wrapped: {song_result_wrapped:?}, found_counter: {found_counter}, dup_counter: {dup_counter}, error_counter: {error_counter}, paths_by_track_id: {paths_by_track_id:?}, album_folder: {album_folder:?})"
-    );
-    match song_result_wrapped {
-        Ok(song_result) => {
-            *found_counter += 1;
-            let prev_value = paths_by_track_id.lock().unwrap().insert(
-                TrackId::from_id(id.clone())?,
-                song_result.path(),
-            );
-            if let Some(prev_value) = prev_value {
-                *dup_counter += 1;
-                let key = &TrackId::from_id(&id)?;
-                match paths_by_track_id.lock().unwrap().get(key) {
-                    Some(entry) => {
-                        debug!("prev_value for {} was {:?}", entry.display(), prev_value);
-                    } 
-                    None => {
-                        debug!("prev_value for {} was {:?}", key, prev_value);
-                    }
-                }
-            }
-        }
-        Err(e) => {
-            *error_counter += 1;
-            error!("Error on retrieving song path at album_folder {album_folder:?}: {e}");
-        }
+/// commit_write moves a [StagedWrite] over its original and records it in `changes` if a manifest was requested.
+/// `fs_retries` is how many times to retry the replacement on transient filesystem errors.
+fn commit_write(staged_write: &StagedWrite, changes: &Mutex<Vec<Change>>, fs_retries: u32) -> Result<()> {
+    write::replace_original(&staged_write.path, &staged_write.temp_path, fs_retries)?;
+    if let Some(old_hash) = &staged_write.old_hash {
+        record_change(&staged_write.path, old_hash, staged_write.kind, changes)?;
     }
+    Ok(())
+}
 
+/// record_change hashes the just replaced file at `path` and records it in `changes` along with the hash of the
+/// original, `old_hash`.
+fn record_change(path: &Path, old_hash: &str, kind: ChangeKind, changes: &Mutex<Vec<Change>>) -> Result<()> {
+    changes.lock().unwrap().push(Change {
+        path: path.to_path_buf(),
+        old_hash: old_hash.to_owned(),
+        new_hash: changes::hash_file(path)?,
+        timestamp: changes::unix_timestamp(),
+        kind,
+    });
     Ok(())
 }
 
-/// chunk_hashmap partitions a [HashMap] into `N` chunks, with the remainder in the final chunk.
+/// WRITE_THREAD_PREFIX starts the name of every thread tagging a file, so the panic hook can tell a panic that only
+/// fails that file from one that has to end the run.
+const WRITE_THREAD_PREFIX: &str = "write ";
+
+/// PARTIAL_FAILURE_EXIT_CODE is the exit code of a run that finished but couldn't tag some files.
+const PARTIAL_FAILURE_EXIT_CODE: u8 = 2;
+
+/// chunk_hashmap partitions a [HashMap] into chunks of `chunk_size`, with the remainder in the final chunk.
 /// The type generics `U` and `V` are the types of HashMap's keys and values, respectively.
 /// `map` is the HashMap to chunk.
-/// `total_len` is the total length of the HashMap if chunking should be based on something other than `map.len()`
-/// (such as if the values are [Vec]s), otherwise None.
 /// `map_values` is a closure that is passed to [Iterator::flat_map] on the Vec<(U, V)> representation of the HashMap
-/// before chunking occurs if the values need to be remapped somehow, such as if, again, the values are [Vec]s,
-/// and you want the chunks to flatten those Vecs; otherwise, pass None::<fn(&(U, V)) -> Vec<(U, V)>>.
-fn chunk_hashmap<const N: usize, U: Clone, V: Clone>(
+/// before chunking occurs if the values need to be remapped somehow, such as if the values are [Vec]s and you want
+/// the chunks to flatten those Vecs; otherwise, pass None::<fn(&(U, V)) -> Vec<(U, V)>>.
+/// Every chunk but the last holds exactly `chunk_size` entries, and there are no empty chunks.
+fn chunk_hashmap<U: Clone, V: Clone>(
     map: HashMap<U, V>,
-    total_len: Option<usize>,
+    chunk_size: usize,
     map_values: Option<impl FnMut(&(U, V)) -> Vec<(U, V)>>
 ) -> Vec<Vec<(U, V)>> {
-    let len = total_len.unwrap_or(map.len());
-    let num_chunks = (len as f64 / N as f64).ceil() as usize;
     let mut iter_as_vec = map.into_iter().collect::<Vec<(U, V)>>();
     if let Some(value_mapper) = map_values {
         iter_as_vec = iter_as_vec.iter().flat_map(value_mapper).collect::<Vec<(U, V)>>();
     }
-    let iter_as_chunks: (&[[(U, V); N]], &[(U, V)]) = iter_as_vec.as_chunks::<N>();
-    (0..num_chunks)
-        .map(|i| {
-            if num_chunks == 1 {
-                if len < N {
-                    iter_as_chunks.1.to_vec()
-                } else {
-                    iter_as_chunks.0[i].to_vec()
-                }
-            } else {
-                if i < num_chunks - 1 {
-                    iter_as_chunks.0[i].to_vec()
-                } else {
-                    iter_as_chunks.1.to_vec()
-                }
-            }
-        })
-        .collect()
+    iter_as_vec.chunks(chunk_size).map(<[(U, V)]>::to_vec).collect()
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Handle background panics in threads or futures
-    let default_panic = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_panic(info);
-        std::process::exit(1);
-    }));
-
-    env_logger::init();
-    dotenvy::dotenv()?;
-
-    let base_path = env::var("BASE_PATH")?;
-    println!("Getting folders in {base_path}");
-    let paths_by_track_id: Arc<Mutex<HashMap<TrackId<'_>, PathBuf>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let all_songs: Vec<_> = fs::read_dir(base_path)?
-        .filter(|entry| entry.as_ref().unwrap().file_type().unwrap().is_dir())
-        .flat_map(|artist_folder| fs::read_dir(artist_folder.as_ref().unwrap().path()))
-        .flatten()
-        .flat_map(|album_folder| fs::read_dir(album_folder.unwrap().path()))
-        .map(|album_folder| album_folder.collect::<Vec<_>>())
-        .collect();
+/// Fetched is the result of [fetch_genres]: the combined (normalized but not yet sorted) genres of every track Spotify
+/// returned, the track metadata it returned along the way, the tracks it didn't return, and the tracks whose requests
+/// failed.
+struct Fetched {
+    genres_by_track: HashMap<TrackId<'static>, Vec<String>>,
+    track_info: HashMap<TrackId<'static>, TrackInfo>,
+    missing_tracks: Vec<TrackId<'static>>,
+    failures: Vec<Failure>,
+}
 
-    let mut found_counter = 0;
-    let mut not_found_counter = 0;
-    let mut error_counter = 0;
-    let mut dup_counter = 0;
+/// connect_spotify authenticates with Spotify using `--client-id` and `--client-secret` (or their environment
+/// variables), sending requests to `--api-base-url` if it's set. Returns the client and its client ID.
+async fn connect_spotify(args: &Args) -> Result<(Arc<ClientCredsSpotify>, String)> {
+    let (Some(client_id), Some(client_secret)) = (&args.client_id, &args.client_secret) else {
+        return Err(anyhow!(
+            "Spotify credentials are missing; pass --client-id and --client-secret or set RSPOTIFY_CLIENT_ID and RSPOTIFY_CLIENT_SECRET"
+        ));
+    };
+    let spotify_creds = Credentials::new(client_id, client_secret);
+    let client_id = client_id.clone();
 
-    println!("Processing folders...");
-    for album_folder in all_songs {
-        let song_ids_file = album_folder
-            .iter()
-            .find(|entry| entry.as_ref().unwrap().file_name() == ".song_ids");
-        if let Some(file) = song_ids_file {
-            let song_ids_str = fs::read_to_string(file.as_ref().unwrap().path())?;
-            let song_ids: Vec<Vec<String>> = if !song_ids_str.is_empty() {
-                song_ids_str
-                    .lines()
-                    .map(|line| line.split('\t').map(|s| s.to_owned()).collect::<Vec<_>>())
-                    .collect()
-            } else {
-                continue;
-            };
-            for id in song_ids {
-                let song = album_folder
-                    .iter()
-                    .find(|entry| *entry.as_ref().unwrap().file_name() == **id.get(4).unwrap());
-                match song {
-                    Some(song_result_wrapped) => {
-                        insert_song_path(
-                            id.get(0).unwrap().to_string(),
-                            song_result_wrapped,
-                            &mut found_counter,
-                            &mut dup_counter,
-                            &mut error_counter,
-                            Arc::clone(&paths_by_track_id),
-                            &album_folder,
-                        )?;
-                    }
-                    None => {
-                        // Try again with base_path prefix
-                        let song = album_folder.iter().find(|entry| {
-                            *entry.as_ref().unwrap().path().as_os_str() == **id.get(4).unwrap()
-                        });
-                        match song {
-                            Some(song_result_wrapped) => {
-                                insert_song_path(
-                                    id.get(0).unwrap().to_string(),
-                                    song_result_wrapped,
-                                    &mut found_counter,
-                                    &mut dup_counter,
-                                    &mut error_counter,
-                                    Arc::clone(&paths_by_track_id),
-                                    &album_folder,
-                                )?;
-                            }
-                            None => {
-                                not_found_counter += 1;
-                                error!("No song found matching song_id at {id:?}");
-                            }
-                        }
-                    }
-                }
-            }
-        } else {
-            error!(
-                "No .song_ids file found for album folder {:?}",
-                album_folder
-            )
-        }
+    // Tokens are refreshed by api::with_retries instead, which makes sure concurrent requests only refresh once
+    let mut spotify_config = Config {
+        token_refreshing: false,
+        ..Config::default()
+    };
+    if let Some(api_base_url) = &args.api_base_url {
+        spotify_config.api_base_url = api_base_url.clone();
     }
+    let spotify = Arc::new(ClientCredsSpotify::with_config(spotify_creds, spotify_config));
 
-    println!("Tracks found successfully: {found_counter}");
-    println!("Tracks not found: {not_found_counter}");
-    println!("Duplicates: {dup_counter}");
-    println!("Errors: {error_counter}");
+    spotify.request_token().await?;
 
-    println!("Grabbing genres from Spotify...");
-    let spotify_creds = Credentials::from_env().unwrap();
+    Ok((spotify, client_id))
+}
 
-    let spotify = Arc::new(ClientCredsSpotify::new(spotify_creds));
+/// fetch_genres looks up the artists of every track in `paths_by_track_id` on `spotify` and combines their genres
+/// into genres for each track. `client_id` is what `--concurrency-auto` remembers the probed concurrency under.
+/// Each track is requested once, however many files it's matched to.
+async fn fetch_genres(
+    args: &Args,
+    spotify: Arc<ClientCredsSpotify>,
+    client_id: String,
+    paths_by_track_id: &PathsByTrackId,
+) -> Result<Fetched> {
+    let fetch_concurrency = if args.concurrency_auto && !paths_by_track_id.is_empty() {
+        let mut known = concurrency::load(&args.concurrency_file)?;
+        match known.get(&client_id) {
+            Some(&fetch_concurrency) => {
+                println!("Using the previously probed fetch concurrency of {fetch_concurrency}");
+                fetch_concurrency
+            }
+            None => {
+                println!("Probing Spotify's rate limit...");
+                let sample: Vec<TrackId> = paths_by_track_id.keys().take(args.chunk_size).cloned().collect();
+                let fetch_concurrency = concurrency::probe(&spotify, &sample).await?;
+                println!("Settled on a fetch concurrency of {fetch_concurrency}");
+                known.insert(client_id, fetch_concurrency);
+                concurrency::save(&args.concurrency_file, &known)?;
+                fetch_concurrency
+            }
+        }
+    } else {
+        args.fetch_concurrency
+    };
+    let semaphore = Arc::new(Semaphore::new(fetch_concurrency.max(1)));
 
-    spotify.request_token().await.unwrap();
+    let cache = cache::load(&args.cache_file, args.case_fold_locale)?;
+    let cached_genres: Arc<HashMap<String, Vec<String>>> = Arc::new(match args.refresh_cache {
+        true => HashMap::new(),
+        false => cache.iter().map(|(artist, cached)| (artist.clone(), cached.genres.clone())).collect(),
+    });
+    let cache_hits = Arc::new(AtomicUsize::new(0));
+    let cache_writer = cache::CacheWriter::spawn(args.cache_file.clone(), cache, args.case_fold_locale);
 
     let genres_by_artist: Arc<Mutex<HashMap<ArtistId, Vec<String>>>> =
         Arc::new(Mutex::new(HashMap::new()));
     let genres_by_track: Arc<Mutex<HashMap<TrackId, Vec<String>>>> =
         Arc::new(Mutex::new(HashMap::new()));
+    let track_info: Arc<Mutex<HashMap<TrackId, TrackInfo>>> = Arc::new(Mutex::new(HashMap::new()));
+    let missing_tracks: Arc<Mutex<Vec<TrackId>>> = Arc::new(Mutex::new(Vec::new()));
+    let failures: Arc<Mutex<Vec<Failure>>> = Arc::new(Mutex::new(Vec::new()));
     let mut genre_tasks = vec![];
 
-    const CHUNK_SIZE: usize = 50;
-    let path_chunks = chunk_hashmap::<CHUNK_SIZE, TrackId, PathBuf>(
-        paths_by_track_id.lock().unwrap().clone(),
-        None,
-        None::<for <'a, 'b> fn(&'a (TrackId<'b>, PathBuf)) -> Vec<(TrackId<'b>, PathBuf)>>
+    let path_chunks = chunk_hashmap::<TrackId, Vec<PathBuf>>(
+        paths_by_track_id.clone(),
+        args.chunk_size,
+        None::<for <'a, 'b> fn(&'a (TrackId<'b>, Vec<PathBuf>)) -> Vec<(TrackId<'b>, Vec<PathBuf>)>>
     );
     debug!("path_chunks: {path_chunks:?}");
     let mut i = 0;
@@ -247,38 +198,128 @@ async fn main() -> Result<()> {
         i += 1;
         if path_chunk.len() > 0 {
             let spotify = spotify.clone();
+            let semaphore = Arc::clone(&semaphore);
             let genres_by_artist = Arc::clone(&genres_by_artist);
             let genres_by_track = Arc::clone(&genres_by_track);
-            let num_paths = paths_by_track_id.lock().unwrap().len() as u64;
+            let track_info = Arc::clone(&track_info);
+            let missing_tracks = Arc::clone(&missing_tracks);
+            let failures = Arc::clone(&failures);
+            let combine_mode = args.artist_genre_combine;
+            let case_fold_locale = args.case_fold_locale;
+            let max_genres_per_artist = args.max_genres_per_artist;
+            let api_retries = args.api_retries;
+            let chunk_size = args.chunk_size;
+            let prefer_album_genres = args.prefer_album_genres;
+            let cached_genres = Arc::clone(&cached_genres);
+            let cache_hits = Arc::clone(&cache_hits);
+            let cache_sender = cache_writer.sender();
             genre_tasks.push(tokio::spawn(async move {
-                // Try to prevent 429s
-                let rand_millis = rand::rng().random_range(0..(num_paths * 10));
-                tokio::time::sleep(Duration::from_millis(rand_millis)).await;
-                
-                let res = spotify
-                .tracks(
-                    path_chunk.into_iter().map(|(track, _)| track.clone()),
-                    None,
-                )
-                .await.unwrap();
+                let _permit = semaphore.acquire().await.unwrap();
+
+                let requested: Vec<TrackId> = path_chunk.iter().map(|(track, _)| track.clone()).collect();
+                let paths: PathsByTrackId = path_chunk.into_iter().collect();
+                let fail = |track: &TrackId<'static>, reason: String| {
+                    failures.lock().unwrap().extend(paths.get(track).into_iter().flatten().map(|path| Failure {
+                        path: path.clone(),
+                        track_id: track.id().to_owned(),
+                        genres: Vec::new(),
+                        phase: Phase::Fetch,
+                        reason: reason.clone(),
+                    }));
+                };
+                let fetch = || api::fetch_tracks(&spotify, &requested);
+                let res = match api::with_retries(&spotify, api_retries, fetch).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!("Failed to fetch tracks {i}: {e}");
+                        for track in &requested {
+                            fail(track, e.to_string());
+                        }
+                        return;
+                    }
+                };
+                let (res, missing) = api::align_tracks(&requested, res);
+                if !missing.is_empty() {
+                    error!("Tracks not returned by Spotify {i}: {missing:?}");
+                    missing_tracks.lock().unwrap().extend(missing);
+                }
                 let mut artists_by_track: HashMap<TrackId, Vec<ArtistId>> = HashMap::new();
-                for track in res {
-                    let id = track.id.unwrap();
-                    let artists = track.artists.clone();
-                    artists_by_track.insert(
-                        id,
-                        artists
-                            .into_iter()
-                            .map(|artist| artist.id.as_ref().unwrap().to_owned())
-                            .collect(),
+                let mut album_by_track: HashMap<TrackId, AlbumId> = HashMap::new();
+                for (id, track) in res {
+                    if let Some(album) = &track.album.id {
+                        album_by_track.insert(id.clone(), album.clone());
+                    }
+                    track_info.lock().unwrap().insert(
+                        id.clone(),
+                        TrackInfo {
+                            name: track.name.clone(),
+                            artists: track.artists.iter().map(|artist| artist.name.clone()).collect(),
+                            artist_ids: track
+                                .artists
+                                .iter()
+                                .filter_map(|artist| artist.id.as_ref().map(|id| id.id().to_owned()))
+                                .collect(),
+                            album: track.album.name.clone(),
+                            track_number: track.track_number,
+                            disc_number: track.disc_number,
+                            duration_secs: track.duration.num_seconds(),
+                            album_type: track.album.album_type.clone(),
+                            album_artists: track.album.artists.iter().map(|artist| artist.name.clone()).collect(),
+                        },
                     );
+                    let artists = track
+                        .artists
+                        .iter()
+                        .filter_map(|artist| {
+                            if artist.id.is_none() {
+                                warn!("Artist {} of track {id:?} has no Spotify ID, leaving it out", artist.name);
+                            }
+                            artist.id.clone()
+                        })
+                        .collect();
+                    artists_by_track.insert(id, artists);
                 }
                 debug!("artists_by_track {i}: {artists_by_track:?}");
-                let mut artists_by_track_orig = artists_by_track.clone();
-                let artists_len = artists_by_track.iter().fold(0, |acc, (_, artists)| acc + artists.len());
-                let artist_chunks: Vec<Vec<(TrackId<'_>, Vec<ArtistId<'_>>)>> = chunk_hashmap::<CHUNK_SIZE, TrackId, Vec<ArtistId>>(
-                    artists_by_track, 
-                    Some(artists_len),
+                let mut album_genres_by_track: HashMap<TrackId, Vec<String>> = HashMap::new();
+                if prefer_album_genres {
+                    let mut albums: Vec<AlbumId> = album_by_track.values().cloned().collect();
+                    albums.sort_by(|a, b| a.id().cmp(b.id()));
+                    albums.dedup();
+                    let mut genres_by_album: HashMap<AlbumId, Vec<String>> = HashMap::new();
+                    for album_chunk in albums.chunks(api::ALBUM_CHUNK_SIZE) {
+                        match api::fetch_album_genres(&spotify, album_chunk.to_vec(), api_retries).await {
+                            Ok(res) => genres_by_album.extend(res),
+                            Err(e) => warn!("Failed to fetch albums {i}, using artist genres for their tracks: {e}"),
+                        }
+                    }
+                    album_genres_by_track = genres::album_genres_by_track(&album_by_track, &genres_by_album);
+                    // Tracks with album genres don't need their artists looked up at all
+                    artists_by_track.retain(|track, _| !album_genres_by_track.contains_key(track));
+                }
+                let artists_by_track_orig = artists_by_track.clone();
+                // Only look up artists that aren't cached or already fetched by another chunk
+                {
+                    let mut genres_by_artist = genres_by_artist.lock().unwrap();
+                    for artists in artists_by_track.values_mut() {
+                        artists.retain(|artist| {
+                            if genres_by_artist.contains_key(artist) {
+                                return false;
+                            }
+                            match cached_genres.get(artist.id()) {
+                                Some(genres) => {
+                                    genres_by_artist.insert(artist.clone(), genres.clone());
+                                    cache_hits.fetch_add(1, Ordering::Relaxed);
+                                    false
+                                }
+                                None => true,
+                            }
+                        });
+                    }
+                }
+                artists_by_track.retain(|_, artists| !artists.is_empty());
+                let artist_chunks: Vec<Vec<(TrackId<'_>, Vec<ArtistId<'_>>)>> = chunk_hashmap::<TrackId, Vec<ArtistId>>(
+                    artists_by_track,
+                    chunk_size,
                     Some(Box::new(for <'a, 'b, 'c>
                         |(track, artists): &'a (TrackId<'b>, Vec<ArtistId<'c>>)| -> Vec<(TrackId<'b>, Vec<ArtistId<'c>>)> {
                             artists.into_iter().map(|artist|
@@ -289,147 +330,800 @@ async fn main() -> Result<()> {
                 );
                 let artist_chunks: Vec<Vec<Vec<ArtistId<'_>>>> = artist_chunks.into_iter().map(|chunk| chunk.into_iter().map(|(_, artists)| artists).collect()).collect();
                 debug!("artist_chunks {i}: {artist_chunks:?}");
+                let mut failed_artists: HashMap<ArtistId, String> = HashMap::new();
                 for artist_chunk in artist_chunks {
                     if artist_chunk.len() > 0 {
-                        let res = spotify.artists(artist_chunk.into_iter().flatten().collect::<Vec<ArtistId>>()).await.unwrap();
-                        for artist in res {
-                            genres_by_artist.lock().unwrap().insert(artist.id, artist.genres);
+                        let artist_chunk: Vec<ArtistId> = artist_chunk.into_iter().flatten().collect();
+                        match api::fetch_artist_genres(&spotify, artist_chunk.clone(), Some(&cache_sender), api_retries).await {
+                            Ok(res) => {
+                                genres_by_artist.lock().unwrap().extend(res);
+                            }
+                            Err(e) => {
+                                error!("Failed to fetch artists {i}: {e}");
+                                failed_artists.extend(artist_chunk.into_iter().map(|artist| (artist, e.to_string())));
+                            }
                         }
                     }
                 }
                 debug!("genres_by_artist {i}: {genres_by_artist:?}");
-                for (artist, genres) in genres_by_artist.lock().unwrap().iter() {
-                    debug!("artist {i}: {artist:?}");
-                    artists_by_track_orig.retain(|track, artists| {
-                        debug!("artists {i}: {artists:?}");
-                        if artists.contains(&artist) {
-                            genres_by_track
-                                .lock()
-                                .unwrap()
-                                .entry(track.clone())
-                                .and_modify(|existing_genres| existing_genres.append(&mut genres.clone()))
-                                .or_insert(genres.clone());
-                            if artists.len() == 1 {
-                                debug!("{i}: removed");
-                                false
-                            } else if artists.len() > 1 {
-                                let artist_idx = artists.iter().position(|art| *art == *artist);
-                                artists.remove(artist_idx.unwrap());
-                                debug!("{i}: decremented");
-                                true
-                            } else {
-                                error!("Unknown state in artist_by_track_orig.retain: artist: {artist:?}, genres_by_track: {genres_by_track:?}");
-                                false
+                let genres_by_artist = genres_by_artist.lock().unwrap();
+                let mut genres_by_track = genres_by_track.lock().unwrap();
+                for (track, genres) in album_genres_by_track {
+                    genres_by_track.insert(track, genres::normalize_genres_in_order(&genres, case_fold_locale));
+                }
+                for (track, artists) in artists_by_track_orig {
+                    if let Some(reason) = artists.iter().find_map(|artist| failed_artists.get(artist)) {
+                        fail(&track, reason.clone());
+                        continue;
+                    }
+                    let artist_genres: Vec<&[String]> = artists
+                        .iter()
+                        .filter_map(|artist| genres_by_artist.get(artist).map(Vec::as_slice))
+                        .collect();
+                    if artist_genres.len() != artists.len() {
+                        error!("Artists without genres for track {track:?} {i}: {artists:?}");
+                    }
+                    genres_by_track.insert(track, genres::combine_artist_genres(
+                        combine_mode,
+                        &artist_genres,
+                        case_fold_locale,
+                        max_genres_per_artist,
+                    ));
+                }
+            }));
+        }
+    }
+
+    let bar = progress::bar(genre_tasks.len(), "Fetching");
+    join_all(genre_tasks.into_iter().map(|task| {
+        let bar = bar.clone();
+        async move {
+            let result = task.await;
+            bar.inc(1);
+            result
+        }
+    }))
+    .await;
+    bar.finish_and_clear();
+    // The genres are already fetched, so a cache that can't be saved only costs the next run some lookups
+    if let Err(e) = cache_writer.finish().await {
+        warn!(
+            "Couldn't save the artist cache to {}, so its new artists will be looked up again next run: {e:#}",
+            args.cache_file.display()
+        );
+    }
+    println!("Artists found in the cache: {}", cache_hits.load(Ordering::Relaxed));
+
+    Ok(Fetched {
+        genres_by_track: std::mem::take(&mut *genres_by_track.lock().unwrap()),
+        track_info: std::mem::take(&mut *track_info.lock().unwrap()),
+        missing_tracks: std::mem::take(&mut *missing_tracks.lock().unwrap()),
+        failures: std::mem::take(&mut *failures.lock().unwrap()),
+    })
+}
+
+/// Written is the result of [write_all]: the manifest entries for every replaced file, the paths that were
+/// replaced, the files that couldn't be, and how many were skipped because they were already up to date.
+struct Written {
+    changes: Vec<Change>,
+    completed: HashSet<PathBuf>,
+    failures: Vec<Failure>,
+    skipped: HashSet<PathBuf>,
+}
+
+/// write_all writes the genres in `genres_by_track` to every matching file in `paths_by_track_id`,
+/// joined according to `genre_join`.
+/// With `--transactional`, nothing is replaced unless every file was remuxed successfully.
+/// With `--backup-dir`, each original is first backed up at its path relative to `base_path`.
+fn write_all(
+    args: &Args,
+    mount: &MountGuard,
+    base_path: &Path,
+    paths_by_track_id: &PathsByTrackId,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    genre_join: &GenreJoin,
+) -> Written {
+    let changes: Mutex<Vec<Change>> = Mutex::new(Vec::new());
+    let staged: Mutex<Vec<StagedWrite>> = Mutex::new(Vec::new());
+    let completed: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    let failures: Mutex<Vec<Failure>> = Mutex::new(Vec::new());
+    let skipped: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    let write_concurrency = args.write_concurrency.unwrap_or_else(|| {
+        thread::available_parallelism().map_or(1, |parallelism| parallelism.get())
+    });
+    let writes: Vec<(&TrackId<'static>, &Vec<String>, &PathBuf)> = genres_by_track
+        .iter()
+        .flat_map(|(track, genres)| {
+            let paths = paths_by_track_id.get(track).map(Vec::as_slice).unwrap_or_default();
+            if paths.is_empty() {
+                error!("No file matched {track:?}, so it can't be tagged");
+            }
+            paths.iter().map(move |path| (track, genres, path))
+        })
+        .collect();
+    let bar = progress::bar(writes.len(), "Writing");
+    thread::scope(|scope| {
+        let mut handles = VecDeque::new();
+        for (track, genres, path) in writes {
+            // Waiting on the oldest file is enough to keep at most write_concurrency remuxes in flight
+            if handles.len() >= write_concurrency.max(1) {
+                let (track, genres, path, handle) = handles.pop_front().unwrap();
+                join_write(handle, track, genres, path, mount, &failures);
+                bar.inc(1);
+            }
+            let (changes, staged, completed, skipped) = (&changes, &staged, &completed, &skipped);
+            let handle = thread::Builder::new()
+                .name(format!("{WRITE_THREAD_PREFIX}{}", path.display()))
+                .spawn_scoped(scope, move || -> WriteResult {
+                    if mount.gave_up() {
+                        return Ok(());
+                    }
+                    let opts = write::WriteOpts {
+                        track_id: track.id(),
+                        genre_join,
+                        mount,
+                        only_untouched: args.only_untouched,
+                        ignore_state_hash: args.ignore_state_hash,
+                        backup_dir: args.backup_dir.as_deref(),
+                        base_path,
+                        hash_original: args.changes.is_some(),
+                        fs_retries: args.fs_retries,
+                        stage_only: args.transactional,
+                    };
+                    match write::write_genre_tag(path, genres, &opts)? {
+                        WriteOutcome::Skipped(skip_reason) => {
+                            if !args.quiet_skip {
+                                info!("Skipping {}: {skip_reason}", path.display());
                             }
+                            skipped.lock().unwrap().insert(path.clone());
+                            completed.lock().unwrap().insert(path.clone());
                         }
-                        else {
-                            debug!("{i}: skipped");
-                            true
+                        WriteOutcome::Staged {
+                            temp_path,
+                            old_hash,
+                            kind,
+                        } => staged.lock().unwrap().push(StagedWrite {
+                            track: track.clone(),
+                            path: path.clone(),
+                            temp_path,
+                            old_hash,
+                            kind,
+                        }),
+                        WriteOutcome::Tagged { old_hash, kind } => {
+                            if let Some(old_hash) = &old_hash {
+                                mount
+                                    .retry("Recording the change", || record_change(path, old_hash, kind, changes))
+                                    .map_err(|e| (Phase::Replace, e))?;
+                            }
+                            if args.verify {
+                                verify_write(args, mount, base_path, path, genres, genre_join)
+                                    .map_err(|e| (Phase::Verify, e))?;
+                            }
+                            completed.lock().unwrap().insert(path.clone());
                         }
+                    }
+                    Ok(())
+                });
+            match handle {
+                Ok(handle) => handles.push_back((track, genres, path, handle)),
+                Err(e) => failures.lock().unwrap().push(Failure {
+                    path: path.clone(),
+                    track_id: track.id().to_owned(),
+                    genres: genres.clone(),
+                    phase: Phase::Remux,
+                    reason: format!("Couldn't start a thread: {e}"),
+                }),
+            }
+        }
+
+        for (track, genres, path, handle) in handles {
+            join_write(handle, track, genres, path, mount, &failures);
+            bar.inc(1);
+        }
+    });
+    bar.finish_and_clear();
+
+    let mut failures = failures.into_inner().unwrap();
+    if args.transactional {
+        let staged = staged.into_inner().unwrap();
+        let staged_bytes: u64 = staged
+            .iter()
+            .filter_map(|staged_write| fs::metadata(&staged_write.temp_path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        println!(
+            "Staged {} files using {:.1} MiB of temporary disk space",
+            staged.len(),
+            staged_bytes as f64 / (1024.0 * 1024.0)
+        );
+        if !failures.is_empty() || mount.gave_up() {
+            for staged_write in &staged {
+                let _ = fs::remove_file(&staged_write.temp_path);
+            }
+            println!("{} files failed to stage, so no files were modified", failures.len());
+        } else {
+            println!("Committing staged files...");
+            for staged_write in staged {
+                let genres = genres_by_track.get(&staged_write.track).cloned().unwrap_or_default();
+                let committed = mount
+                    .retry("Replacing the original", || commit_write(&staged_write, &changes, args.fs_retries))
+                    .map_err(|e| (Phase::Replace, e))
+                    .and_then(|()| match args.verify {
+                        true => verify_write(args, mount, base_path, &staged_write.path, &genres, genre_join)
+                            .map_err(|e| (Phase::Verify, e)),
+                        false => Ok(()),
                     });
+                match committed {
+                    Ok(()) => {
+                        completed.lock().unwrap().insert(staged_write.path);
+                    }
+                    Err((phase, e)) => {
+                        error!("Failed to commit {}: {e}", staged_write.path.display());
+                        failures.push(Failure {
+                            track_id: staged_write.track.id().to_owned(),
+                            genres,
+                            path: staged_write.path,
+                            phase,
+                            reason: e.to_string(),
+                        });
+                    }
                 }
-                if artists_by_track_orig.len() != 0 {
-                    error!("Artists without matching tracks {i}: {artists_by_track_orig:?}");
-                }
-            }));
+            }
         }
     }
 
-    join_all(genre_tasks).await;
+    Written {
+        changes: changes.into_inner().unwrap(),
+        completed: completed.into_inner().unwrap(),
+        failures,
+        skipped: skipped.into_inner().unwrap(),
+    }
+}
 
-    for (_track, genres) in genres_by_track.lock().unwrap().iter_mut() {
-        genres.sort();
-        genres.dedup();
+/// verify_write reads back the genre tag of the just replaced `path` and checks it's `genres` joined according to
+/// `genre_join`. If it isn't and `--backup-dir` is set, the original is restored from its backup (relative to
+/// `base_path`) before the mismatch is returned.
+fn verify_write(
+    args: &Args,
+    mount: &MountGuard,
+    base_path: &Path,
+    path: &Path,
+    genres: &[String],
+    genre_join: &GenreJoin,
+) -> Result<()> {
+    let Err(e) = mount.retry("Verifying", || write::verify_genres(path, genres, genre_join)) else {
+        return Ok(());
+    };
+    match &args.backup_dir {
+        Some(backup_dir) => match mount.retry("Restoring the backup", || write::restore_backup(path, base_path, backup_dir)) {
+            Ok(()) => Err(anyhow!("{e}; restored the original from its backup")),
+            Err(restore_error) => Err(anyhow!("{e}; couldn't restore the original from its backup: {restore_error}")),
+        },
+        None => Err(e),
     }
+}
 
-    debug!("genres_by_track: {genres_by_track:?}");
+/// WriteResult is what each write thread returns: nothing, or the phase it failed in and why.
+type WriteResult = Result<(), (Phase, anyhow::Error)>;
 
-    println!("Writing genres to disk...");
+/// join_write waits for the write thread tagging `path` (as `track` with `genres`) and records it in `failures` if it
+/// failed or panicked, so each file's errors and panics stay with that file and the rest of the run carries on.
+/// Failures while the mount is down aren't recorded, since the run is about to stop anyway.
+fn join_write(
+    handle: ScopedJoinHandle<'_, WriteResult>,
+    track: &TrackId,
+    genres: &[String],
+    path: &Path,
+    mount: &MountGuard,
+    failures: &Mutex<Vec<Failure>>,
+) {
+    let (phase, reason) = match handle.join() {
+        Ok(Ok(())) => return,
+        Ok(Err(_)) if mount.gave_up() => return,
+        Ok(Err((phase, e))) => (phase, e.to_string()),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            let _ = fs::remove_file(write::temp_path_for(path));
+            (Phase::Remux, format!("Panicked: {message}"))
+        }
+    };
+    error!("Failed to tag {}: {reason}", path.display());
+    failures.lock().unwrap().push(Failure {
+        path: path.to_path_buf(),
+        track_id: track.id().to_owned(),
+        genres: genres.to_vec(),
+        phase,
+        reason,
+    });
+}
 
+/// print_planned_tags prints, sorted by path, each path of each track and the genre tag [write_all] would give it.
+fn print_planned_tags(
+    paths_by_track_id: &PathsByTrackId,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    genre_join: &GenreJoin,
+) {
+    let mut planned: Vec<_> = genres_by_track
+        .iter()
+        .filter_map(|(track, genres)| Some((paths_by_track_id.get(track)?, genres)))
+        .flat_map(|(paths, genres)| paths.iter().map(move |path| (path, genres)))
+        .collect();
+    planned.sort();
+    for (path, genres) in planned {
+        match genre_join {
+            GenreJoin::Delimiter(delimiter) => println!("{}\t{}", path.display(), genres.join(delimiter)),
+            GenreJoin::MultiValue => println!("{}\t{genres:?}", path.display()),
+        }
+    }
+    println!("Files that would be written: {}", planned.len());
+}
+
+/// write_m3u writes the `--write-m3u` playlist of `genres_by_track` to `m3u_path`, listing each path in
+/// `paths_by_track_id` under its new name in `renamed` if it was renamed.
+fn write_m3u(
+    m3u_path: &Path,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    paths_by_track_id: &PathsByTrackId,
+    track_info: &HashMap<TrackId<'static>, TrackInfo>,
+    renamed: &HashMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let paths_by_track_id: PathsByTrackId = paths_by_track_id
+        .iter()
+        .map(|(track, paths)| {
+            let paths = paths.iter().map(|path| renamed.get(path).unwrap_or(path).clone()).collect();
+            (track.clone(), paths)
+        })
+        .collect();
+    let written = m3u::write_playlist(m3u_path, genres_by_track, &paths_by_track_id, track_info)?;
+    println!("Wrote {written} tracks to {}", m3u_path.display());
+    Ok(())
+}
+
+/// warm_cache looks up the genres of every artist listed in the file at `artists_path` (one ID, URI, or URL per
+/// line; blank lines and lines starting with `#` are ignored) that isn't cached yet, and adds them to the cache.
+async fn warm_cache(args: &Args, artists_path: &Path) -> Result<()> {
+    let mut artists: Vec<ArtistId<'static>> = Vec::new();
+    for line in fs::read_to_string(artists_path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let id = line
+            .split_once("open.spotify.com/artist/")
+            .map_or(line, |(_, rest)| rest.split(['?', '/']).next().unwrap_or_default());
+        artists.push(ArtistId::from_id_or_uri(id)?.into_static());
+    }
+    artists.sort_by(|a, b| a.id().cmp(b.id()));
+    artists.dedup();
+
+    let cache = cache::load(&args.cache_file, args.case_fold_locale)?;
+    let (cached, uncached): (Vec<_>, Vec<_>) =
+        artists.into_iter().partition(|artist| cache.contains_key(artist.id()));
+    println!("Artists already cached: {}", cached.len());
+
+    let (spotify, _) = connect_spotify(args).await?;
+    let writer = cache::CacheWriter::spawn(args.cache_file.clone(), cache, args.case_fold_locale);
+    let sender = writer.sender();
+    let mut newly_cached = 0;
+    let mut failed = 0;
+    for chunk in uncached.chunks(args.chunk_size) {
+        match api::fetch_artist_genres(&spotify, chunk.to_vec(), Some(&sender), args.api_retries).await {
+            Ok(fetched) => newly_cached += fetched.len(),
+            Err(e) => {
+                error!("Failed to fetch {} artists: {e}", chunk.len());
+                failed += chunk.len();
+            }
+        }
+    }
+    drop(sender);
+    writer.finish().await?;
+    println!("Artists newly cached: {newly_cached}");
+    println!("Artists that failed to fetch: {failed}");
+    println!("Wrote cache to {}", args.cache_file.display());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    // Handle background panics in threads or futures
+    let default_panic = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_panic(info);
+        if !thread::current().name().is_some_and(|name| name.starts_with(WRITE_THREAD_PREFIX)) {
+            std::process::exit(1);
+        }
+    }));
+
+    progress::init_logger();
+    // Settings can come from a .env file, but don't have to
+    dotenvy::dotenv().ok();
+    let args = Args::parse();
     ffmpeg_next::init()?;
 
-    let genres_lock = genres_by_track.lock().unwrap();
-    thread::scope(|scope| {
-        for (track, genres) in genres_lock.iter() {
-            scope.spawn(|| {
-                let paths = paths_by_track_id.lock().unwrap();
-                let path = paths.get(track).unwrap();
-                info!("Processing file {}", path.display());
-                let mut ictx = format::input(path).unwrap();
-                let context_or_stream = if ictx.metadata().iter().count() != 0 {
-                    ContextOrStream::Context(&ictx)
-                } else {
-                    ContextOrStream::Stream(&ictx.streams().best(media::Type::Audio).unwrap())
-                };
-                let mut temp_path = path.clone();
-                temp_path.set_extension(
-                    path.extension().unwrap().to_string_lossy().into_owned() + ".tmp",
-                );
-                let mut octx = format::output_as(&temp_path, "ogg").unwrap();
-                let mut stream_mapping: Vec<i32> = vec![0; ictx.nb_streams() as _];
-                let mut ist_time_bases = vec![Rational(0, 1); ictx.nb_streams() as _];
-                let mut ost_index = 0;
-                for (ist_index, ist) in ictx.streams().enumerate() {
-                    let ist_medium = ist.parameters().medium();
-                    if ist_medium != media::Type::Audio {
-                        stream_mapping[ist_index] = -1;
-                        continue;
-                    }
-                    stream_mapping[ist_index] = ost_index;
-                    ist_time_bases[ist_index] = ist.time_base();
-                    ost_index += 1;
-                    let mut ost = octx.add_stream(encoder::find(codec::Id::OPUS)).unwrap();
-                    ost.set_parameters(ist.parameters());
-                    unsafe {
-                        (*ost.parameters().as_mut_ptr()).codec_tag = 0;
-                    }
+    if let Some(Command::WarmCache { artists }) = &args.command {
+        return warm_cache(&args, artists).await.map(|()| ExitCode::SUCCESS);
+    }
+
+    let base_path = match (&args.flat_dir, &args.base_path) {
+        (Some(flat_dir), _) => flat_dir.to_string_lossy().into_owned(),
+        (None, Some(base_path)) => base_path.to_string_lossy().into_owned(),
+        (None, None) => return Err(anyhow!("No library folder given; pass it as an argument or set BASE_PATH")),
+    };
+    let mount = MountGuard::new(
+        PathBuf::from(&base_path),
+        args.mount_retries,
+        Duration::from_secs(args.mount_retry_delay),
+    );
+    let mut last_run = match &args.last_run_file {
+        Some(last_run_file) => last_run::load(last_run_file)?,
+        None => LastRun::new(),
+    };
+    let mut skipped_counter = 0;
+    let scanned_paths: Vec<PathBuf>;
+    let mut flat_matches = Vec::new();
+    let mut fetch_failures = Vec::new();
+    let mut track_info = HashMap::new();
+    let mut counters = Counters::default();
+    let genre_map = match &args.genre_map {
+        Some(genre_map_path) => Some(genre_map::load(genre_map_path, args.case_fold_locale)?),
+        None => None,
+    };
+
+    let (paths_by_track_id, genres_by_track) = match &args.reprocess_errors {
+        Some(report_path) => {
+            println!("Reprocessing failed files from {}", report_path.display());
+            let (paths_by_track_id, genres_by_track) = report::load_failures(report_path)?;
+            scanned_paths = paths_by_track_id.values().flatten().cloned().collect();
+            (paths_by_track_id, genres_by_track)
+        }
+        None => {
+            let mut spotify = None;
+            let mut paths_by_track_id = match &args.flat_dir {
+                Some(flat_dir) => {
+                    println!("Matching loose files in {base_path}");
+                    let (client, client_id) = connect_spotify(&args).await?;
+                    let flat_scan = flat::scan_flat_dir(&client, flat_dir, &mount, args.api_retries).await?;
+                    let count_by = |method| {
+                        flat_scan.matches.iter().filter(|flat_match| flat_match.method == method).count()
+                    };
+                    println!("Matched by embedded ID: {}", count_by(Some(MatchMethod::EmbeddedId)));
+                    println!("Matched by search: {}", count_by(Some(MatchMethod::Search)));
+                    println!("Not matched: {}", count_by(None));
+                    flat_matches = flat_scan.matches;
+                    spotify = Some((client, client_id));
+                    flat_scan.paths_by_track_id
                 }
-                match context_or_stream {
-                    ContextOrStream::Context(ictx) => {
-                        let mut octx_metadata = ictx.metadata().to_owned();
-                        octx_metadata.set("genre", &genres.join(","));
-                        octx.set_metadata(octx_metadata);
+                None => {
+                    println!("Getting folders in {base_path}");
+                    let scan = scan::scan_library(&base_path, &mount, args.filter.as_ref())?;
+
+                    println!("Tracks found successfully: {}", scan.found_counter);
+                    println!("Tracks not found: {}", scan.not_found_counter);
+                    println!("Duplicates: {}", scan.dup_counter);
+                    println!("Errors: {}", scan.error_counter);
+                    counters.found = scan.found_counter;
+                    counters.not_found = scan.not_found_counter;
+                    counters.duplicates = scan.dup_counter;
+                    counters.errors = scan.error_counter;
+                    println!("Files not referenced by any .song_ids: {}", scan.unreferenced.len());
+                    if let Some(unreferenced_path) = &args.report_unreferenced {
+                        json::write_atomic(unreferenced_path, &scan.unreferenced)?;
+                        println!("Wrote unreferenced files to {}", unreferenced_path.display());
                     }
-                    ContextOrStream::Stream(input) => {
-                        let mut output = octx
-                            .streams_mut()
-                            .find(|s| {
-                                codec::context::Context::from_parameters(s.parameters())
-                                    .unwrap()
-                                    .medium()
-                                    == media::Type::Audio
-                            })
-                            .unwrap();
-                        let mut output_metadata = input.metadata().to_owned();
-                        output_metadata.set("genre", &genres.join(","));
-                        output.set_metadata(output_metadata);
+                    let mut paths_by_track_id = scan.paths_by_track_id;
+                    if args.report_duplicates.is_some() || args.quarantine_duplicates.is_some() {
+                        let duplicates = duplicates::resolve_duplicates(&paths_by_track_id);
+                        if let Some(duplicates_path) = &args.report_duplicates {
+                            json::write_atomic(duplicates_path, &duplicates)?;
+                            println!("Wrote {} duplicated tracks to {}", duplicates.len(), duplicates_path.display());
+                        }
+                        if let Some(quarantine_dir) = &args.quarantine_duplicates {
+                            let moved = duplicates::quarantine(&duplicates, Path::new(&base_path), quarantine_dir)?;
+                            println!("Moved {moved} redundant files to {}", quarantine_dir.display());
+                            // Only tag the files still in the library
+                            for paths in paths_by_track_id.values_mut().filter(|paths| paths.len() > 1) {
+                                paths.retain(|path| path.exists());
+                            }
+                        }
                     }
+                    paths_by_track_id
                 }
+            };
 
-                octx.write_header().unwrap();
+            if let Some(Command::Formats) = args.command {
+                formats::print_formats(&paths_by_track_id)?;
+                return Ok(ExitCode::SUCCESS);
+            }
 
-                for (stream, mut packet) in ictx.packets() {
-                    let ist_index = stream.index();
-                    let ost_index = stream_mapping[ist_index];
-                    if ost_index < 0 {
-                        continue;
+            if args.only_new {
+                for paths in paths_by_track_id.values_mut() {
+                    paths.retain(|path| match last_run::is_new(path, &last_run) {
+                        Ok(true) => true,
+                        Ok(false) => {
+                            if !args.quiet_skip {
+                                info!("Skipping {}: not modified since its album was last processed", path.display());
+                            }
+                            skipped_counter += 1;
+                            false
+                        }
+                        Err(e) => {
+                            error!("Couldn't check whether {} is new, processing it anyway: {e}", path.display());
+                            true
+                        }
+                    });
+                }
+                paths_by_track_id.retain(|_, paths| !paths.is_empty());
+                println!("Skipped (not modified since last run): {skipped_counter}");
+            }
+
+            // Every file the run covers before --limit cuts it short, which is what an album has to finish to count
+            // as processed
+            scanned_paths = paths_by_track_id.values().flatten().cloned().collect();
+
+            if let Some(limit) = args.limit
+                && scan::limit_files(&mut paths_by_track_id, limit)
+            {
+                println!("Limited to the first {limit} files by path");
+            }
+
+            if args.estimate {
+                let track_chunks: Vec<usize> = chunk_hashmap::<TrackId, Vec<PathBuf>>(
+                    paths_by_track_id.clone(),
+                    args.chunk_size,
+                    None::<for<'a, 'b> fn(&'a (TrackId<'b>, Vec<PathBuf>)) -> Vec<(TrackId<'b>, Vec<PathBuf>)>>,
+                )
+                .iter()
+                .map(Vec::len)
+                .filter(|len| *len > 0)
+                .collect();
+                let fetch_concurrency = match args.concurrency_auto {
+                    true => args
+                        .client_id
+                        .as_ref()
+                        .and_then(|client_id| concurrency::load(&args.concurrency_file).ok()?.get(client_id).copied())
+                        .unwrap_or(args.fetch_concurrency),
+                    false => args.fetch_concurrency,
+                };
+                let estimate = estimate::estimate_fetch(&track_chunks, args.chunk_size, fetch_concurrency);
+                println!("Tracks to fetch: {}", paths_by_track_id.len());
+                println!("Track requests: {}", estimate.track_requests);
+                println!("Artist requests (estimated): {}", estimate.artist_requests);
+                println!(
+                    "Estimated fetch time at a concurrency of {fetch_concurrency}: {:.0}s",
+                    estimate.secs
+                );
+                return Ok(ExitCode::SUCCESS);
+            }
+
+            println!("Grabbing genres from Spotify...");
+            let (spotify, client_id) = match spotify {
+                Some(spotify) => spotify,
+                None => connect_spotify(&args).await?,
+            };
+            let fetched = fetch_genres(&args, spotify, client_id, &paths_by_track_id).await?;
+            println!("Tracks not returned by Spotify: {}", fetched.missing_tracks.len());
+            counters.missing_on_spotify = fetched.missing_tracks.len();
+            println!("Tracks that failed to fetch: {}", fetched.failures.len());
+            fetch_failures = fetched.failures;
+
+            let mut genres_by_track = fetched.genres_by_track;
+            for (_track, genres) in genres_by_track.iter_mut() {
+                if let Some(genre_map) = &genre_map {
+                    *genres = genre_map.apply(genres, args.drop_unmapped_genres);
+                }
+                *genres = genres::normalize_genres_in_order(genres, args.case_fold_locale);
+            }
+            let compilations = genres::compilation_albums(&paths_by_track_id, &fetched.track_info);
+            genres::apply_scope(args.genre_scope, &mut genres_by_track, &paths_by_track_id, &compilations);
+            // Capped only now, so --max-genres holds for what's actually written rather than before an album's
+            // genres are pooled or --genre-map adds some
+            genres::cap_genres(&mut genres_by_track, args.max_genres);
+
+            if let Some(cmd) = &args.genre_filter_cmd {
+                let timeout = Duration::from_secs(args.genre_filter_timeout);
+                for (track, genres) in genres_by_track.iter_mut() {
+                    match filter_cmd::run_filter(cmd, track.id(), genres, timeout) {
+                        Ok(filtered) => *genres = filtered,
+                        Err(e) => error!("--genre-filter-cmd failed for {track:?}, keeping its genres as they were: {e}"),
                     }
-                    let ost = octx.stream(ost_index as _).unwrap();
-                    packet.rescale_ts(ist_time_bases[ist_index], ost.time_base());
-                    packet.set_position(-1);
-                    packet.set_stream(ost_index as _);
-                    packet.write_interleaved(&mut octx).unwrap();
                 }
+            }
 
-                octx.write_trailer().unwrap();
+            debug!("genres_by_track: {genres_by_track:?}");
 
-                fs::remove_file(path).unwrap();
-                fs::rename(temp_path, path).unwrap();
-            });
+            if args.genre_stats {
+                let counts = genres::genre_counts(genres_by_track.values());
+                println!("Top {} of {} genres by track count:", args.genre_stats_top.min(counts.len()), counts.len());
+                for (genre, count) in counts.iter().take(args.genre_stats_top) {
+                    println!("{count:>7}  {genre}");
+                }
+                if let Some(stats_path) = &args.genre_stats_file {
+                    let distribution: String =
+                        counts.iter().map(|(genre, count)| format!("{count}\t{genre}\n")).collect();
+                    fs::write(stats_path, distribution)?;
+                    println!("Wrote the full genre distribution to {}", stats_path.display());
+                }
+            }
+
+            track_info = fetched.track_info;
+            (paths_by_track_id, genres_by_track)
         }
-    });
+    };
+
+    if let Some(db_path) = &args.sqlite {
+        let written =
+            sqlite::write_genres(db_path, &genres_by_track, &paths_by_track_id, changes::unix_timestamp())?;
+        println!("Wrote genres for {written} tracks to {}", db_path.display());
+        if let Some(m3u_path) = &args.write_m3u {
+            write_m3u(m3u_path, &genres_by_track, &paths_by_track_id, &track_info, &HashMap::new())?;
+        }
+        println!("Finished!");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let genre_join = if args.genres_delimiter_detect {
+        let genre_join = genres::detect_delimiter(genres_by_track.values());
+        match &genre_join {
+            GenreJoin::Delimiter(delimiter) => println!("Using {delimiter:?} as the genre delimiter"),
+            GenreJoin::MultiValue => {
+                println!("Every candidate delimiter appears in some genre, so writing one genre tag per genre")
+            }
+        }
+        genre_join
+    } else if args.genre_multi_value {
+        GenreJoin::MultiValue
+    } else {
+        GenreJoin::Delimiter(args.genre_delimiter.clone())
+    };
+
+    if args.dry_run {
+        print_planned_tags(&paths_by_track_id, &genres_by_track, &genre_join);
+        println!("Dry run, so no files were modified");
+        return Ok(ExitCode::SUCCESS);
+    }
 
+    println!("Writing genres to disk...");
+
+    let mut written = write_all(&args, &mount, Path::new(&base_path), &paths_by_track_id, &genres_by_track, &genre_join);
+    let mut renamed = HashMap::new();
+    if let Some(pattern) = &args.rename_pattern {
+        renamed = rename::rename_all(
+            pattern,
+            paths_by_track_id
+                .iter()
+                .flat_map(|(track, paths)| paths.iter().map(move |path| (track, path)))
+                .filter(|(_, path)| written.completed.contains(*path)),
+            &track_info,
+        );
+        for change in written.changes.iter_mut() {
+            if let Some(new_path) = renamed.get(&change.path) {
+                change.path = new_path.clone();
+            }
+        }
+        println!("Renamed: {}", renamed.len());
+    }
+    if let Some(m3u_path) = &args.write_m3u {
+        write_m3u(m3u_path, &genres_by_track, &paths_by_track_id, &track_info, &renamed)?;
+    }
+    let write_failed_counter = written.failures.len();
+    let mut failures = fetch_failures;
+    failures.extend(written.failures);
+    skipped_counter += written.skipped.len();
+
+    if let Some(changes_path) = &args.changes {
+        changes::write_manifest(changes_path, &written.changes)?;
+        println!("Wrote {} changes to {}", written.changes.len(), changes_path.display());
+    }
+
+    if let Some(report_path) = &args.report {
+        let mut tracks = report::track_reports(
+            &paths_by_track_id,
+            &genres_by_track,
+            &track_info,
+            &failures,
+            &written.skipped,
+            &written.completed,
+        );
+        for track in tracks.iter_mut() {
+            if let Some(new_path) = renamed.get(&track.path) {
+                track.path = new_path.clone();
+            }
+        }
+        counters.tagged = written.completed.len() - written.skipped.len();
+        counters.skipped = skipped_counter;
+        counters.failed = failures.len();
+        report::save(
+            report_path,
+            &Report {
+                failures: failures.clone(),
+                flat_matches,
+                counters,
+                tracks,
+            },
+        )?;
+        println!("Wrote report to {}", report_path.display());
+    }
+
+    if let Some(last_run_file) = &args.last_run_file {
+        last_run::record(&mut last_run, &scanned_paths, &written.completed, changes::unix_timestamp());
+        last_run::save(last_run_file, &last_run)?;
+    }
+
+    if mount.gave_up() {
+        return Err(anyhow!(
+            "Gave up waiting for {base_path} to come back; rerun with --only-new to resume"
+        ));
+    }
+
+    println!("Skipped: {skipped_counter}");
+    println!("Failed: {}", failures.len());
+    if !failures.is_empty() {
+        report::print_failure_table(&failures);
+    }
+    if args.transactional && write_failed_counter != 0 {
+        return Err(anyhow!("{write_failed_counter} files failed, so no files were modified"));
+    }
+    if !failures.is_empty() {
+        println!("Finished with failures");
+        return Ok(ExitCode::from(PARTIAL_FAILURE_EXIT_CODE));
+    }
     println!("Finished!");
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// chunk_sizes chunks a map of `len` entries into chunks of 4 and returns the size of each chunk, checking that
+    /// every entry ends up in exactly one of them.
+    fn chunk_sizes(len: u32) -> Vec<usize> {
+        let map: HashMap<u32, u32> = (0..len).map(|n| (n, n * 10)).collect();
+        let chunks = chunk_hashmap(map.clone(), 4, None::<fn(&(u32, u32)) -> Vec<(u32, u32)>>);
+        let rejoined: HashMap<u32, u32> = chunks.iter().flatten().copied().collect();
+        assert_eq!(rejoined, map);
+        assert_eq!(chunks.iter().map(Vec::len).sum::<usize>(), map.len());
+        chunks.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn empty_map_has_no_chunks() {
+        assert_eq!(chunk_sizes(0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn map_smaller_than_a_chunk_is_one_chunk() {
+        assert_eq!(chunk_sizes(3), [3]);
+    }
+
+    #[test]
+    fn map_of_exactly_one_chunk_is_one_chunk() {
+        assert_eq!(chunk_sizes(4), [4]);
+    }
+
+    #[test]
+    fn map_of_exactly_two_chunks_has_no_empty_remainder() {
+        assert_eq!(chunk_sizes(8), [4, 4]);
+    }
+
+    #[test]
+    fn remainder_goes_in_the_last_chunk() {
+        assert_eq!(chunk_sizes(10), [4, 4, 2]);
+    }
+
+    #[test]
+    fn values_are_flattened_before_chunking() {
+        let map = HashMap::from([(1, vec!['a', 'b', 'c']), (2, vec!['d', 'e'])]);
+        let chunks = chunk_hashmap(
+            map,
+            2,
+            Some(|(key, values): &(i32, Vec<char>)| values.iter().map(|value| (*key, vec![*value])).collect()),
+        );
+        assert_eq!(chunks.iter().map(Vec::len).collect::<Vec<_>>(), [2, 2, 1]);
+    }
 }
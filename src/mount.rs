@@ -0,0 +1,71 @@
+//! Riding out a library on a network mount that drops mid-run.
+
+use log::{error, warn};
+use std::fmt::Display;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// MountGuard retries file operations that fail while the library's base path is unreachable,
+/// backing off until the mount comes back or `retries` attempts have been made.
+/// Once it gives up, [MountGuard::gave_up] stays true so the rest of the run can stop early and flush its state.
+pub struct MountGuard {
+    base_path: PathBuf,
+    retries: u32,
+    delay: Duration,
+    gave_up: AtomicBool,
+}
+
+impl MountGuard {
+    pub fn new(base_path: PathBuf, retries: u32, delay: Duration) -> Self {
+        Self {
+            base_path,
+            retries,
+            delay,
+            gave_up: AtomicBool::new(false),
+        }
+    }
+
+    /// is_available returns whether the base path can currently be read.
+    pub fn is_available(&self) -> bool {
+        fs::read_dir(&self.base_path).is_ok()
+    }
+
+    /// gave_up returns whether a previous [MountGuard::retry] exhausted its retries waiting for the mount.
+    pub fn gave_up(&self) -> bool {
+        self.gave_up.load(Ordering::Relaxed)
+    }
+
+    /// retry runs `op`, and if it fails while the base path is unreachable, waits (doubling the delay each time)
+    /// and runs it again. Errors that happen while the base path is reachable are returned immediately.
+    /// `what` describes the operation for logging.
+    pub fn retry<T, E: Display>(&self, what: &str, mut op: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            let err = match op() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            if self.gave_up() || self.is_available() {
+                return Err(err);
+            }
+            if attempt == self.retries {
+                error!(
+                    "{} is still unreachable after {attempt} retries, giving up: {what}: {err}",
+                    self.base_path.display()
+                );
+                self.gave_up.store(true, Ordering::Relaxed);
+                return Err(err);
+            }
+            let delay = self.delay * 2u32.saturating_pow(attempt);
+            warn!(
+                "{} looks disconnected ({what}: {err}), retrying in {delay:?}",
+                self.base_path.display()
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Progress bars for the long phases of a run (scanning, fetching, writing).
+//!
+//! Bars are drawn on stderr and hidden when stderr isn't a terminal, so piped output stays clean. Log output goes
+//! through the same [MultiProgress], so log lines print above the bars instead of tearing through them.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use std::sync::LazyLock;
+
+/// PROGRESS draws every bar of the run.
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// init_logger sets up `env_logger` (configured by `RUST_LOG` as usual) to log through [PROGRESS].
+pub fn init_logger() {
+    let logger = env_logger::Builder::from_env(env_logger::Env::default()).build();
+    let level = logger.filter();
+    if LogWrapper::new(PROGRESS.clone(), logger).try_init().is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// bar adds a bar for `len` steps labeled `message`. Call [ProgressBar::finish_and_clear] once the phase is over.
+pub fn bar(len: usize, message: &'static str) -> ProgressBar {
+    let bar = PROGRESS.add(ProgressBar::new(len as u64));
+    bar.set_style(
+        ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} ({eta} left)")
+            .unwrap_or_else(|_| ProgressStyle::default_bar())
+            .progress_chars("=> "),
+    );
+    bar.set_message(message);
+    bar
+}
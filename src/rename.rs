@@ -0,0 +1,215 @@
+//! Renaming tagged files after their Spotify metadata with `--rename-pattern`, keeping `.song_ids` pointing at them.
+
+use crate::m3u::TrackInfo;
+use anyhow::Result;
+use log::{error, info};
+use rspotify::model::TrackId;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// FIELDS are the placeholders a rename pattern can use, each written as `{field}`.
+const FIELDS: [&str; 6] = ["title", "artist", "artists", "album", "track_number", "disc_number"];
+
+/// SONG_IDS_FILE_FIELD is the index of the tab-separated `.song_ids` field holding the song's file name.
+const SONG_IDS_FILE_FIELD: usize = 4;
+
+/// parse_pattern checks that `pattern` only uses known [FIELDS] and doesn't try to move files out of their
+/// album folder, for use as a clap value parser.
+pub fn parse_pattern(pattern: &str) -> Result<String, String> {
+    if pattern.contains(['/', '\\']) {
+        return Err("the pattern names a file in its album folder, so it can't contain path separators".to_owned());
+    }
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {pattern:?}"))?;
+        let field = &rest[start + 1..start + end];
+        if !FIELDS.contains(&field) {
+            return Err(format!("unknown field {{{field}}}, expected one of {}", FIELDS.join(", ")));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(pattern.to_owned())
+}
+
+/// render fills the [FIELDS] in `pattern` in with `info`. Track and disc numbers are zero-padded to two digits.
+pub fn render(pattern: &str, info: &TrackInfo) -> String {
+    pattern
+        .replace("{title}", &info.name)
+        .replace("{artists}", &info.artists.join(", "))
+        .replace("{artist}", info.artists.first().map_or("", String::as_str))
+        .replace("{album}", &info.album)
+        .replace("{track_number}", &format!("{:02}", info.track_number))
+        .replace("{disc_number}", &format!("{:02}", info.disc_number))
+}
+
+/// sanitize makes `name` safe to use as a file name on common filesystems: characters that are reserved on any of
+/// them (and control characters) become `_`, and leading/trailing dots and spaces are trimmed.
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_matches(|c| c == '.' || c == ' ');
+    match name {
+        "" => "_".to_owned(),
+        name => name.to_owned(),
+    }
+}
+
+/// target_path returns the path `path` should be renamed to given its rendered `name`: in the same folder, with the
+/// same extension, and with " (2)", " (3)", ... appended if another file (or one already claimed in `claimed`)
+/// has that name.
+fn target_path(path: &Path, name: &str, claimed: &HashSet<PathBuf>) -> PathBuf {
+    let folder = path.parent().unwrap_or(Path::new(""));
+    let with_name = |name: &str| {
+        let mut file_name = OsString::from(name);
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        folder.join(file_name)
+    };
+    let mut target = with_name(name);
+    let mut n = 2;
+    while target != path && (target.exists() || claimed.contains(&target)) {
+        target = with_name(&format!("{name} ({n})"));
+        n += 1;
+    }
+    target
+}
+
+/// song_ids_folder returns the folder whose `.song_ids` lists the song at `path`: the closest one above it that has a
+/// `.song_ids`, since entries can point into a subfolder (like `cd1/track.ogg`). Songs outside any album with a
+/// `.song_ids` (like in a `--flat-dir`) have none.
+fn song_ids_folder(path: &Path) -> Option<&Path> {
+    path.ancestors().skip(1).find(|folder| folder.join(".song_ids").exists())
+}
+
+/// update_song_ids rewrites the `.song_ids` file in `album` so entries pointing at any old path in `renames` point at
+/// its new path instead, in the same form (bare file name, full path, or path relative to the album).
+/// Albums without a `.song_ids` are left alone.
+fn update_song_ids(album: &Path, renames: &[(&PathBuf, &PathBuf)]) -> Result<()> {
+    let song_ids_path = album.join(".song_ids");
+    if !song_ids_path.exists() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(&song_ids_path)?;
+    let mut updated = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let (line, ending) = match line.strip_suffix('\n') {
+            Some(line) => (line, "\n"),
+            None => (line, ""),
+        };
+        let mut fields: Vec<String> = line.split('\t').map(str::to_owned).collect();
+        if let Some(file) = fields.get_mut(SONG_IDS_FILE_FIELD) {
+            let file_path = Path::new(file.trim_end_matches('\r'));
+            let resolved = if file_path.is_absolute() { file_path.to_path_buf() } else { album.join(file_path) };
+            if let Some((_, new_path)) = renames.iter().find(|(old_path, _)| **old_path == resolved) {
+                let new_file_name = new_path.file_name().unwrap_or_default();
+                let new_file = file_path.with_file_name(new_file_name);
+                let carriage_return = if file.ends_with('\r') { "\r" } else { "" };
+                *file = new_file.to_string_lossy().into_owned() + carriage_return;
+            }
+        }
+        updated.push_str(&fields.join("\t"));
+        updated.push_str(ending);
+    }
+    let temp_path = album.join(".song_ids.tmp");
+    fs::write(&temp_path, updated)?;
+    fs::rename(&temp_path, &song_ids_path)?;
+    Ok(())
+}
+
+/// rename_all renames each file in `paths` (with the [TrackId] it was matched to) according to `pattern`, using the
+/// metadata in `track_info`, then updates the `.song_ids` listing each one to match. Files without metadata or that
+/// already have the right name are left alone, and a file that can't be renamed is logged and skipped.
+/// Returns the old and new path of every renamed file.
+pub fn rename_all<'a>(
+    pattern: &str,
+    paths: impl IntoIterator<Item = (&'a TrackId<'static>, &'a PathBuf)>,
+    track_info: &HashMap<TrackId<'static>, TrackInfo>,
+) -> HashMap<PathBuf, PathBuf> {
+    let mut claimed = HashSet::new();
+    let mut renamed: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut paths: Vec<_> = paths.into_iter().collect();
+    paths.sort_by(|(_, a), (_, b)| a.cmp(b));
+    for (track, path) in paths {
+        let Some(info) = track_info.get(track) else {
+            continue;
+        };
+        let target = target_path(path, &sanitize(&render(pattern, info)), &claimed);
+        claimed.insert(target.clone());
+        if target == *path {
+            continue;
+        }
+        match fs::rename(path, &target) {
+            Ok(()) => {
+                info!("Renamed {} to {}", path.display(), target.display());
+                renamed.insert(path.clone(), target);
+            }
+            Err(e) => error!("Failed to rename {} to {}: {e}", path.display(), target.display()),
+        }
+    }
+
+    let mut renames_by_album: HashMap<&Path, Vec<(&PathBuf, &PathBuf)>> = HashMap::new();
+    for (old_path, new_path) in &renamed {
+        if let Some(album) = song_ids_folder(old_path) {
+            renames_by_album.entry(album).or_default().push((old_path, new_path));
+        }
+    }
+    for (album, renames) in renames_by_album {
+        if let Err(e) = update_song_ids(album, &renames) {
+            error!(
+                "Failed to update {}, so its renamed files won't match on the next run: {e}",
+                album.join(".song_ids").display()
+            );
+        }
+    }
+    renamed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_ids_entry_in_a_subfolder_is_rewritten() {
+        let library = tempfile::tempdir().unwrap();
+        let album = library.path().join("Album");
+        fs::create_dir_all(album.join("cd1")).unwrap();
+        let old_path = album.join("cd1").join("track.ogg");
+        fs::write(&old_path, b"").unwrap();
+        fs::write(album.join(".song_ids"), "0000000000000000000001\t0\tArtist\tTitle\tcd1/track.ogg\n").unwrap();
+        let track = TrackId::from_id("0000000000000000000001").unwrap();
+        let info = TrackInfo {
+            name: "Title".to_owned(),
+            artists: vec!["Artist".to_owned()],
+            artist_ids: Vec::new(),
+            album: "Album".to_owned(),
+            track_number: 1,
+            disc_number: 1,
+            duration_secs: 0,
+            album_type: None,
+            album_artists: Vec::new(),
+        };
+        let track_info = HashMap::from([(track.clone(), info)]);
+
+        let renamed = rename_all("{title}", [(&track, &old_path)], &track_info);
+
+        let new_path = album.join("cd1").join("Title.ogg");
+        assert_eq!(renamed.get(&old_path), Some(&new_path));
+        assert!(new_path.exists());
+        assert_eq!(
+            fs::read_to_string(album.join(".song_ids")).unwrap(),
+            "0000000000000000000001\t0\tArtist\tTitle\tcd1/Title.ogg\n"
+        );
+    }
+}
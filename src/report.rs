@@ -0,0 +1,198 @@
+//! The JSON report of a run, and reading a previous report back in to retry its failures.
+
+use crate::flat::FlatMatch;
+use crate::json;
+use crate::m3u::TrackInfo;
+use crate::scan::PathsByTrackId;
+use anyhow::Result;
+use log::warn;
+use rspotify::model::TrackId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// FAILURE_TABLE_ROWS is the most failures [print_failure_table] lists before summarizing the rest.
+const FAILURE_TABLE_ROWS: usize = 50;
+
+/// Phase is the step of the run a [Failure] happened in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Looking up the track or its artists on Spotify.
+    Fetch,
+    /// Copying the original to `--backup-dir`.
+    Backup,
+    /// Writing the tagged copy to a temp file.
+    #[default]
+    Remux,
+    /// Moving the tagged copy over the original.
+    Replace,
+    /// Reading the genre tag back with `--verify`.
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Phase::Fetch => "fetch",
+            Phase::Backup => "backup",
+            Phase::Remux => "remux",
+            Phase::Replace => "replace",
+            Phase::Verify => "verify",
+        })
+    }
+}
+
+/// Failure is a file that couldn't be tagged, with the genres it should have gotten so it can be retried
+/// without going back to Spotify. Files that failed in [Phase::Fetch] have no genres.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub track_id: String,
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub phase: Phase,
+    pub reason: String,
+}
+
+/// Counters are the totals printed over the course of a run. The scan counters stay 0 unless the library was scanned.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Counters {
+    pub found: i32,
+    pub not_found: i32,
+    pub duplicates: i32,
+    pub errors: i32,
+    pub missing_on_spotify: usize,
+    pub tagged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// TrackStatus is what happened to a track's file in the run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackStatus {
+    /// The file was rewritten with the track's genres.
+    Tagged,
+    /// The file was left alone, e.g. because it already had these genres or a transactional run was rolled back.
+    Skipped,
+    /// The file couldn't be tagged; see the matching [Failure].
+    Failed,
+    /// Spotify didn't return the track, so there were no genres to write.
+    NotFound,
+}
+
+/// TrackReport is one matched track's outcome.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrackReport {
+    pub path: PathBuf,
+    pub track_id: String,
+    pub artist_ids: Vec<String>,
+    pub genres: Vec<String>,
+    pub status: TrackStatus,
+}
+
+/// Report is what's written to `--report`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Report {
+    pub failures: Vec<Failure>,
+    /// How each file was matched in `--flat-dir` mode, empty otherwise.
+    #[serde(default)]
+    pub flat_matches: Vec<FlatMatch>,
+    #[serde(default)]
+    pub counters: Counters,
+    /// Every matched track, sorted by path.
+    #[serde(default)]
+    pub tracks: Vec<TrackReport>,
+}
+
+/// save atomically writes `report` as JSON to `path`.
+pub fn save(path: &Path, report: &Report) -> Result<()> {
+    json::write_atomic(path, report)
+}
+
+/// track_reports returns a [TrackReport] for every path of every track in `paths_by_track_id`, sorted by path.
+/// A track's status comes from whether its path is among `failures`, `skipped`, or `tagged`, in that order, and
+/// otherwise from whether it has genres in `genres_by_track`. Artist IDs come from `track_info`, if Spotify returned
+/// the track.
+pub fn track_reports(
+    paths_by_track_id: &PathsByTrackId,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    track_info: &HashMap<TrackId<'static>, TrackInfo>,
+    failures: &[Failure],
+    skipped: &HashSet<PathBuf>,
+    tagged: &HashSet<PathBuf>,
+) -> Vec<TrackReport> {
+    let failed: HashSet<&Path> = failures.iter().map(|failure| failure.path.as_path()).collect();
+    let mut tracks: Vec<TrackReport> = paths_by_track_id
+        .iter()
+        .flat_map(|(track, paths)| paths.iter().map(move |path| (track, path)))
+        .map(|(track, path)| {
+            let genres = genres_by_track.get(track);
+            let status = if failed.contains(path.as_path()) {
+                TrackStatus::Failed
+            } else if skipped.contains(path) {
+                TrackStatus::Skipped
+            } else if tagged.contains(path) {
+                TrackStatus::Tagged
+            } else if genres.is_none() {
+                TrackStatus::NotFound
+            } else {
+                TrackStatus::Skipped
+            };
+            TrackReport {
+                path: path.clone(),
+                track_id: track.id().to_owned(),
+                artist_ids: track_info.get(track).map(|info| info.artist_ids.clone()).unwrap_or_default(),
+                genres: genres.cloned().unwrap_or_default(),
+                status,
+            }
+        })
+        .collect();
+    tracks.sort_by(|a, b| a.path.cmp(&b.path));
+    tracks
+}
+
+/// load_failures reads the report at `path` and returns the paths and genres of its failed files,
+/// keyed by [TrackId] the same way a fresh scan and fetch would produce them.
+/// Files that failed in [Phase::Fetch] are left out, since there are no genres to retry them with.
+pub fn load_failures(
+    path: &Path,
+) -> Result<(PathsByTrackId, HashMap<TrackId<'static>, Vec<String>>)> {
+    let report: Report = serde_json::from_slice(&fs::read(path)?)?;
+    let mut paths_by_track_id: PathsByTrackId = HashMap::new();
+    let mut genres_by_track = HashMap::new();
+    for failure in report.failures {
+        if failure.phase == Phase::Fetch {
+            warn!("Not retrying {}: its genres were never fetched", failure.path.display());
+            continue;
+        }
+        let track = TrackId::from_id(failure.track_id)?;
+        paths_by_track_id.entry(track.clone()).or_default().push(failure.path);
+        genres_by_track.insert(track, failure.genres);
+    }
+    Ok((paths_by_track_id, genres_by_track))
+}
+
+/// print_failure_table prints `failures` as a table of phase, path, and error, sorted by phase and then error so
+/// failures with the same cause end up together. Only the first [FAILURE_TABLE_ROWS] are listed.
+pub fn print_failure_table(failures: &[Failure]) {
+    let mut failures: Vec<&Failure> = failures.iter().collect();
+    failures.sort_by(|a, b| (a.phase, &a.reason, &a.path).cmp(&(b.phase, &b.reason, &b.path)));
+    let path_width = failures
+        .iter()
+        .take(FAILURE_TABLE_ROWS)
+        .map(|failure| failure.path.display().to_string().chars().count())
+        .max()
+        .unwrap_or(0);
+    println!("{:<7}  {:<path_width$}  ERROR", "PHASE", "PATH");
+    for failure in failures.iter().take(FAILURE_TABLE_ROWS) {
+        let reason = failure.reason.lines().next().unwrap_or_default();
+        println!("{:<7}  {:<path_width$}  {reason}", failure.phase, failure.path.display().to_string());
+    }
+    if failures.len() > FAILURE_TABLE_ROWS {
+        println!("...and {} more", failures.len() - FAILURE_TABLE_ROWS);
+    }
+}
@@ -0,0 +1,360 @@
+//! Scanning the library for `.song_ids` files and matching their entries to song files.
+
+use crate::mount::MountGuard;
+use crate::progress;
+use anyhow::Result;
+use glob::{MatchOptions, Pattern};
+use log::{debug, error, trace};
+use rspotify::model::TrackId;
+use std::fs::{self, DirEntry, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// PathsByTrackId is every matched song path keyed by its [TrackId]. A track matched to more than one file
+/// (e.g. the same song on an album and a compilation) has all of them, in the order they were found.
+pub type PathsByTrackId = HashMap<TrackId<'static>, Vec<PathBuf>>;
+
+/// Scan is the result of [scan_library]: every matched song path keyed by its [TrackId],
+/// the files in album folders that no `.song_ids` entry referenced,
+/// and the success, not found, duplicate, and error counters.
+pub struct Scan {
+    pub paths_by_track_id: PathsByTrackId,
+    pub unreferenced: Vec<PathBuf>,
+    pub found_counter: i32,
+    pub not_found_counter: i32,
+    pub dup_counter: i32,
+    pub error_counter: i32,
+}
+
+/// FolderFilter is a `--filter` pattern: a glob if it has any of `*?[`, otherwise a substring.
+/// Both match case-insensitively against a whole folder name.
+#[derive(Clone, Debug)]
+pub enum FolderFilter {
+    Glob(Pattern),
+    Substring(String),
+}
+
+impl FolderFilter {
+    /// matches returns whether the folder named `name` matches this filter.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            FolderFilter::Glob(pattern) => pattern.matches_with(
+                name,
+                MatchOptions {
+                    case_sensitive: false,
+                    ..MatchOptions::new()
+                },
+            ),
+            FolderFilter::Substring(substring) => name.to_lowercase().contains(substring),
+        }
+    }
+}
+
+/// parse_filter parses a `--filter` pattern into a [FolderFilter] for clap.
+pub fn parse_filter(filter: &str) -> Result<FolderFilter, String> {
+    if filter.contains(['*', '?', '[']) {
+        Pattern::new(filter).map(FolderFilter::Glob).map_err(|e| e.to_string())
+    } else {
+        Ok(FolderFilter::Substring(filter.to_lowercase()))
+    }
+}
+
+/// insert_song_path will add a [PathBuf] matching a given [TrackId] to its paths in paths_by_track_id.
+/// `id` is the TrackId as a [String].
+/// `song_path` is the [Result] of the song file search.
+/// `found_counter`, `dup_counter`, and `error_counter` are references to success, duplicate, and error counters.
+/// `paths_by_track_id` is passed directly. A TrackId that already has a path counts as a duplicate.
+/// `album_folder` is the [Result] of the album folder search.
+fn insert_song_path(
+    id: String,
+    song_path: Result<PathBuf, &Error>,
+    found_counter: &mut i32,
+    dup_counter: &mut i32,
+    error_counter: &mut i32,
+    paths_by_track_id: Arc<Mutex<PathsByTrackId>>,
+    album_folder: &Vec<Result<DirEntry, Error>>,
+) -> Result<()> {
+    trace!(
+        "insert_song_path(id: {id:?}, song_path: {song_path:?}, found_counter: {found_counter}, dup_counter: {dup_counter}, error_counter: {error_counter}, paths_by_track_id: {paths_by_track_id:?}, album_folder: {album_folder:?})"
+    );
+    match song_path {
+        Ok(song_path) => {
+            *found_counter += 1;
+            let mut paths_by_track_id = paths_by_track_id.lock().unwrap();
+            let paths = paths_by_track_id.entry(TrackId::from_id(id.clone())?).or_default();
+            if !paths.is_empty() {
+                *dup_counter += 1;
+                debug!("{id} was already matched to {paths:?}, also matching {}", song_path.display());
+            }
+            if !paths.contains(&song_path) {
+                paths.push(song_path);
+            }
+        }
+        Err(e) => {
+            *error_counter += 1;
+            error!("Error on retrieving song path at album_folder {album_folder:?}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// collect_folders reads `folder` and, recursively, the folders below it, adding the entries of every folder that has
+/// a `.song_ids` file (or has files but no `.song_ids`, below `base_path`) to `folders` along with its path.
+/// Folders below one with a `.song_ids` aren't searched, since its entries can already point into them
+/// (e.g. "cd1/track.ogg"). A subfolder that can't be read is logged and skipped.
+fn collect_folders(
+    folder: &Path,
+    depth: usize,
+    mount: &MountGuard,
+    folders: &mut Vec<(PathBuf, Vec<Result<DirEntry, Error>>)>,
+) -> Result<()> {
+    let entries: Vec<_> = mount.retry("Reading a folder", || fs::read_dir(folder))?.collect();
+    let is_dir = |entry: &DirEntry| entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+    let has_song_ids = entries
+        .iter()
+        .any(|entry| entry.as_ref().is_ok_and(|entry| entry.file_name() == ".song_ids"));
+    if !has_song_ids {
+        for subfolder in entries.iter().filter_map(|entry| entry.as_ref().ok()).filter(|entry| is_dir(entry)) {
+            if let Err(e) = collect_folders(&subfolder.path(), depth + 1, mount, folders) {
+                error!("Couldn't read {}: {e}", subfolder.path().display());
+            }
+        }
+    }
+    let has_files = entries
+        .iter()
+        .any(|entry| entry.as_ref().is_ok_and(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file())));
+    if has_song_ids || (has_files && depth > 0) {
+        folders.push((folder.to_path_buf(), entries));
+    }
+    Ok(())
+}
+
+/// scan_library searches `base_path` at any depth for folders with a `.song_ids` file, whether laid out as artist
+/// and album folders, single `{artist} - {album}` folders, or playlist folders, and matches the entries of each
+/// `.song_ids` file to the song files next to it.
+/// With a `filter`, only folders with a name on the way from `base_path` (e.g. the artist or the album folder) that
+/// matches it are scanned, and the counters only cover those.
+/// Reads that fail while the mount is down are retried through `mount`.
+pub fn scan_library(base_path: &str, mount: &MountGuard, filter: Option<&FolderFilter>) -> Result<Scan> {
+    let paths_by_track_id: Arc<Mutex<PathsByTrackId>> = Arc::new(Mutex::new(HashMap::new()));
+    let mut all_songs = Vec::new();
+    collect_folders(Path::new(base_path), 0, mount, &mut all_songs)?;
+    if let Some(filter) = filter {
+        all_songs.retain(|(folder, _)| {
+            folder
+                .strip_prefix(base_path)
+                .unwrap_or(folder)
+                .components()
+                .any(|name| filter.matches(&name.as_os_str().to_string_lossy()))
+        });
+    }
+
+    let mut found_counter = 0;
+    let mut not_found_counter = 0;
+    let mut error_counter = 0;
+    let mut dup_counter = 0;
+    let mut unreferenced = Vec::new();
+    // Shared by all folders, since a subpath entry references a file in another folder than its .song_ids
+    let mut referenced: HashSet<PathBuf> = HashSet::new();
+
+    println!("Processing folders...");
+    let bar = progress::bar(all_songs.len(), "Scanning");
+    for (_, album_folder) in all_songs {
+        bar.inc(1);
+        let song_ids_file = album_folder
+            .iter()
+            .find(|entry| entry.as_ref().is_ok_and(|entry| entry.file_name() == ".song_ids"));
+        if let Some(Ok(file)) = song_ids_file {
+            let song_ids_path = file.path();
+            let album_path = song_ids_path.parent().unwrap_or(Path::new(""));
+            // Read line by line so a huge .song_ids never has to fit in memory at once
+            let song_ids_file = mount.retry("Opening .song_ids", || File::open(&song_ids_path))?;
+            for (line_index, line) in BufReader::new(song_ids_file).lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        error_counter += 1;
+                        error!("Skipping unreadable line {} of {}: {e}", line_index + 1, song_ids_path.display());
+                        // Invalid UTF-8 only spoils its own line; any other read error would just repeat
+                        match e.kind() {
+                            ErrorKind::InvalidData => continue,
+                            _ => break,
+                        }
+                    }
+                };
+                // Also drops the \r of .song_ids files written on Windows
+                let line = line.trim_end();
+                if line.is_empty() {
+                    continue;
+                }
+                let id: Vec<&str> = line.split('\t').collect();
+                let (track_id, file_name) = match (id.first(), id.get(4)) {
+                    (Some(track_id), Some(file_name)) if TrackId::from_id(*track_id).is_ok() => (*track_id, *file_name),
+                    _ => {
+                        error_counter += 1;
+                        error!(
+                            "Skipping malformed line {} of {}: expected a track ID and at least 5 tab-separated fields, got {line:?}",
+                            line_index + 1,
+                            song_ids_path.display()
+                        );
+                        continue;
+                    }
+                };
+                let song = album_folder
+                    .iter()
+                    .find(|entry| entry.as_ref().is_ok_and(|entry| entry.file_name() == file_name));
+                match song {
+                    Some(song_result_wrapped) => {
+                        if let Ok(song) = song_result_wrapped {
+                            referenced.insert(song.path());
+                        }
+                        insert_song_path(
+                            track_id.to_string(),
+                            song_result_wrapped.as_ref().map(DirEntry::path),
+                            &mut found_counter,
+                            &mut dup_counter,
+                            &mut error_counter,
+                            Arc::clone(&paths_by_track_id),
+                            &album_folder,
+                        )?;
+                    }
+                    None => {
+                        // Try again with base_path prefix
+                        let song = album_folder.iter().find(|entry| {
+                            entry.as_ref().is_ok_and(|entry| entry.path().as_os_str() == file_name)
+                        });
+                        match song {
+                            Some(song_result_wrapped) => {
+                                if let Ok(song) = song_result_wrapped {
+                                    referenced.insert(song.path());
+                                }
+                                insert_song_path(
+                                    track_id.to_string(),
+                                    song_result_wrapped.as_ref().map(DirEntry::path),
+                                    &mut found_counter,
+                                    &mut dup_counter,
+                                    &mut error_counter,
+                                    Arc::clone(&paths_by_track_id),
+                                    &album_folder,
+                                )?;
+                            }
+                            None => {
+                                // Try again as a path relative to the album folder, e.g. "cd1/track.ogg"
+                                let relative_path = Path::new(file_name);
+                                let song_path = album_path.join(relative_path);
+                                if relative_path.components().count() > 1 && song_path.is_file() {
+                                    referenced.insert(song_path.clone());
+                                    insert_song_path(
+                                        track_id.to_string(),
+                                        Ok(song_path),
+                                        &mut found_counter,
+                                        &mut dup_counter,
+                                        &mut error_counter,
+                                        Arc::clone(&paths_by_track_id),
+                                        &album_folder,
+                                    )?;
+                                } else {
+                                    not_found_counter += 1;
+                                    error!("No song found matching song_id at {id:?}");
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        } else {
+            error!(
+                "No .song_ids file found for album folder {:?}",
+                album_folder
+            )
+        }
+        unreferenced.extend(
+            album_folder
+                .iter()
+                .filter_map(|entry| entry.as_ref().ok())
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+                .filter(|entry| entry.file_name() != ".song_ids")
+                .map(DirEntry::path),
+        );
+    }
+    bar.finish_and_clear();
+    unreferenced.retain(|path| !referenced.contains(path));
+    unreferenced.sort();
+
+    Ok(Scan {
+        paths_by_track_id: std::mem::take(&mut *paths_by_track_id.lock().unwrap()),
+        unreferenced,
+        found_counter,
+        not_found_counter,
+        dup_counter,
+        error_counter,
+    })
+}
+
+/// limit_files cuts `paths_by_track_id` down to the first `limit` files by path, dropping tracks left with none.
+/// Returns whether there were more than `limit` to begin with.
+pub fn limit_files(paths_by_track_id: &mut PathsByTrackId, limit: usize) -> bool {
+    let mut all_paths: Vec<&PathBuf> = paths_by_track_id.values().flatten().collect();
+    if all_paths.len() <= limit {
+        return false;
+    }
+    all_paths.sort();
+    let Some(last_kept) = limit.checked_sub(1).map(|last| all_paths[last].clone()) else {
+        paths_by_track_id.clear();
+        return true;
+    };
+    for paths in paths_by_track_id.values_mut() {
+        paths.retain(|path| *path <= last_kept);
+    }
+    paths_by_track_id.retain(|_, paths| !paths.is_empty());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const TRACK_ID: &str = "0000000000000000000001";
+
+    #[test]
+    fn song_ids_entry_can_point_into_a_subfolder() {
+        let library = tempfile::tempdir().unwrap();
+        let album = library.path().join("Artist").join("Album");
+        fs::create_dir_all(album.join("cd1")).unwrap();
+        fs::write(album.join("cd1").join("track.opus"), b"").unwrap();
+        fs::write(album.join("cover.jpg"), b"").unwrap();
+        fs::write(album.join(".song_ids"), format!("{TRACK_ID}\t0\tArtist\tTrack\tcd1/track.opus\n")).unwrap();
+        let mount = MountGuard::new(library.path().to_path_buf(), 0, Duration::ZERO);
+
+        let scan = scan_library(&library.path().to_string_lossy(), &mount, None).unwrap();
+
+        let track = TrackId::from_id(TRACK_ID).unwrap();
+        assert_eq!(scan.paths_by_track_id[&track], [album.join("cd1").join("track.opus")]);
+        assert_eq!((scan.found_counter, scan.not_found_counter, scan.error_counter), (1, 0, 0));
+        assert_eq!(scan.unreferenced, [album.join("cover.jpg")]);
+    }
+
+    #[test]
+    fn unreadable_line_is_counted_and_skipped() {
+        let library = tempfile::tempdir().unwrap();
+        let album = library.path().join("Artist").join("Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(album.join("track.opus"), b"").unwrap();
+        let mut song_ids = b"\xff\xfe not UTF-8\n".to_vec();
+        song_ids.extend(format!("{TRACK_ID}\t0\tArtist\tTrack\ttrack.opus\n").bytes());
+        fs::write(album.join(".song_ids"), song_ids).unwrap();
+        let mount = MountGuard::new(library.path().to_path_buf(), 0, Duration::ZERO);
+
+        let scan = scan_library(&library.path().to_string_lossy(), &mount, None).unwrap();
+
+        let track = TrackId::from_id(TRACK_ID).unwrap();
+        assert_eq!(scan.paths_by_track_id[&track], [album.join("track.opus")]);
+        assert_eq!((scan.found_counter, scan.error_counter), (1, 1));
+    }
+}
@@ -0,0 +1,149 @@
+//! Recording resolved genres in a SQLite database instead of writing them to the song files.
+//!
+//! Schema:
+//! - `tracks(track_id TEXT PRIMARY KEY, path TEXT NOT NULL, updated_at INTEGER NOT NULL)`
+//! - `track_paths(track_id TEXT NOT NULL, path TEXT NOT NULL, PRIMARY KEY (track_id, path))`
+//! - `track_genres(track_id TEXT NOT NULL, genre TEXT NOT NULL, position INTEGER NOT NULL, PRIMARY KEY (track_id, genre))`
+//!
+//! `tracks.path` is the first of a track's paths in sort order; `track_paths` has all of them, for tracks matched to
+//! more than one file (e.g. the same song on an album and a compilation).
+//! Re-runs upsert each track's row and replace its paths and genres, so the database always reflects the latest run
+//! for every track that run resolved. Tracks from earlier runs that weren't resolved again are left alone.
+
+use crate::scan::PathsByTrackId;
+use anyhow::Result;
+use rspotify::{model::TrackId, prelude::*};
+use rusqlite::{Connection, params};
+use std::collections::HashMap;
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tracks (
+        track_id TEXT PRIMARY KEY,
+        path TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS track_paths (
+        track_id TEXT NOT NULL REFERENCES tracks (track_id),
+        path TEXT NOT NULL,
+        PRIMARY KEY (track_id, path)
+    );
+    CREATE TABLE IF NOT EXISTS track_genres (
+        track_id TEXT NOT NULL REFERENCES tracks (track_id),
+        genre TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (track_id, genre)
+    );
+    CREATE INDEX IF NOT EXISTS track_genres_genre ON track_genres (genre);
+";
+
+/// write_genres records every track in `genres_by_track` with its paths from `paths_by_track_id`
+/// into the database at `db_path`, creating it if necessary. Returns the number of tracks written.
+/// A track matched to more than one file gets one `tracks` row, with the first of its paths in sort order, and a
+/// `track_paths` row per file.
+/// `updated_at` is stored on every written track, in seconds since the Unix epoch.
+pub fn write_genres(
+    db_path: &Path,
+    genres_by_track: &HashMap<TrackId<'static>, Vec<String>>,
+    paths_by_track_id: &PathsByTrackId,
+    updated_at: u64,
+) -> Result<usize> {
+    let mut conn = Connection::open(db_path)?;
+    conn.execute_batch(SCHEMA)?;
+
+    let tx = conn.transaction()?;
+    let mut written = 0;
+    {
+        let mut upsert_track = tx.prepare(
+            "INSERT INTO tracks (track_id, path, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (track_id) DO UPDATE SET path = excluded.path, updated_at = excluded.updated_at",
+        )?;
+        let mut delete_paths = tx.prepare("DELETE FROM track_paths WHERE track_id = ?1")?;
+        let mut insert_path = tx.prepare("INSERT OR IGNORE INTO track_paths (track_id, path) VALUES (?1, ?2)")?;
+        let mut delete_genres = tx.prepare("DELETE FROM track_genres WHERE track_id = ?1")?;
+        let mut insert_genre =
+            tx.prepare("INSERT OR IGNORE INTO track_genres (track_id, genre, position) VALUES (?1, ?2, ?3)")?;
+        for (track, genres) in genres_by_track {
+            let paths = paths_by_track_id.get(track).map(Vec::as_slice).unwrap_or_default();
+            let Some(path) = paths.iter().min() else {
+                continue;
+            };
+            upsert_track.execute(params![track.id(), path.to_string_lossy(), updated_at as i64])?;
+            delete_paths.execute(params![track.id()])?;
+            for path in paths {
+                insert_path.execute(params![track.id(), path.to_string_lossy()])?;
+            }
+            delete_genres.execute(params![track.id()])?;
+            for (position, genre) in genres.iter().enumerate() {
+                insert_genre.execute(params![track.id(), genre, position as i64])?;
+            }
+            written += 1;
+        }
+    }
+    tx.commit()?;
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn track(n: u8) -> TrackId<'static> {
+        TrackId::from_id(format!("{n:022}")).unwrap()
+    }
+
+    /// paths_of returns the `track_paths` of `track` in the database at `db_path`, sorted.
+    fn paths_of(db_path: &Path, track: &TrackId) -> Vec<String> {
+        let conn = Connection::open(db_path).unwrap();
+        let mut query = conn.prepare("SELECT path FROM track_paths WHERE track_id = ?1 ORDER BY path").unwrap();
+        query.query_map(params![track.id()], |row| row.get(0)).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn track_matched_to_several_files_keeps_every_path() {
+        let folder = tempfile::tempdir().unwrap();
+        let db_path = folder.path().join("genres.db");
+        let genres_by_track = HashMap::from([(track(1), vec!["rock".to_owned()])]);
+        let paths_by_track_id = HashMap::from([(
+            track(1),
+            vec![PathBuf::from("Compilation/01.ogg"), PathBuf::from("Album/03.ogg")],
+        )]);
+        assert_eq!(write_genres(&db_path, &genres_by_track, &paths_by_track_id, 100).unwrap(), 1);
+        assert_eq!(paths_of(&db_path, &track(1)), ["Album/03.ogg", "Compilation/01.ogg"]);
+        let conn = Connection::open(&db_path).unwrap();
+        let path: String = conn
+            .query_row("SELECT path FROM tracks WHERE track_id = ?1", params![track(1).id()], |row| row.get(0))
+            .unwrap();
+        assert_eq!(path, "Album/03.ogg");
+    }
+
+    #[test]
+    fn rerun_replaces_paths_and_genres() {
+        let folder = tempfile::tempdir().unwrap();
+        let db_path = folder.path().join("genres.db");
+        let paths_by_track_id =
+            HashMap::from([(track(1), vec![PathBuf::from("a/1.ogg"), PathBuf::from("b/1.ogg")])]);
+        let genres_by_track = HashMap::from([(track(1), vec!["rock".to_owned(), "pop".to_owned()])]);
+        write_genres(&db_path, &genres_by_track, &paths_by_track_id, 100).unwrap();
+
+        let paths_by_track_id = HashMap::from([(track(1), vec![PathBuf::from("b/1.ogg")])]);
+        let genres_by_track = HashMap::from([(track(1), vec!["jazz".to_owned()])]);
+        write_genres(&db_path, &genres_by_track, &paths_by_track_id, 200).unwrap();
+
+        assert_eq!(paths_of(&db_path, &track(1)), ["b/1.ogg"]);
+        let conn = Connection::open(&db_path).unwrap();
+        let mut query = conn.prepare("SELECT genre, position FROM track_genres WHERE track_id = ?1").unwrap();
+        let genres: Vec<(String, i64)> = query
+            .query_map(params![track(1).id()], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(genres, [("jazz".to_owned(), 0)]);
+        let updated_at: i64 = conn
+            .query_row("SELECT updated_at FROM tracks WHERE track_id = ?1", params![track(1).id()], |row| row.get(0))
+            .unwrap();
+        assert_eq!(updated_at, 200);
+    }
+}
@@ -0,0 +1,713 @@
+//! Remuxing song files with their genre tag set.
+
+use crate::changes::{self, ChangeKind};
+use crate::genres::GenreJoin;
+use crate::mount::MountGuard;
+use crate::report::Phase;
+use anyhow::{Result, anyhow};
+use base64::prelude::*;
+use ffmpeg_next::{
+    Dictionary, DictionaryRef, Rational, Stream, codec, encoder, ffi,
+    format::{
+        self,
+        context::{Input, Output},
+        stream::Disposition,
+    },
+    media,
+};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::thread;
+use std::time::Duration;
+
+/// ContextOrStream is used to abstract over metadata assigned to a container 
+///  or to a specific stream inside that container.
+enum ContextOrStream<'a> {
+    Context(&'a Input),
+    Stream(&'a Stream<'a>),
+}
+
+/// WriteOpts is how [write_genre_tag] writes a file.
+pub struct WriteOpts<'a> {
+    /// The ID of the track the file was matched to, hashed into its [STATE_TAG].
+    pub track_id: &'a str,
+    pub genre_join: &'a GenreJoin,
+    /// Retries every step that fails while the mount is down.
+    pub mount: &'a MountGuard,
+    /// Skip files that have a [STATE_TAG] at all, whatever it says.
+    pub only_untouched: bool,
+    /// Write files even if their [STATE_TAG] says they already have these genres.
+    pub ignore_state_hash: bool,
+    /// Back the original up into this folder, at its path relative to `base_path`, before remuxing.
+    pub backup_dir: Option<&'a Path>,
+    pub base_path: &'a Path,
+    /// Hash the original before it's replaced, for a changes manifest.
+    pub hash_original: bool,
+    pub fs_retries: u32,
+    /// Leave the remuxed file at its temp path instead of replacing the original, for `--transactional`.
+    pub stage_only: bool,
+}
+
+/// WriteOutcome is what [write_genre_tag] did with a file.
+pub enum WriteOutcome {
+    /// The file was left alone, for the given reason.
+    Skipped(&'static str),
+    /// The file was remuxed to `temp_path`, but the original is untouched.
+    Staged {
+        temp_path: PathBuf,
+        old_hash: Option<String>,
+        kind: ChangeKind,
+    },
+    /// The original was replaced by the remuxed file.
+    Tagged { old_hash: Option<String>, kind: ChangeKind },
+}
+
+/// MERGED_DELIMITER is what ffmpeg joins repeated Vorbis comments with when reading them back, and so what multi-value
+/// genres are joined with in containers that can't repeat a tag (see [stored_genre_join]).
+const MERGED_DELIMITER: &str = ";";
+
+/// STATE_TAG is the tag key the state hash is stored under, next to the genre tag.
+pub const STATE_TAG: &str = "zotify_genre_tagger_state";
+
+/// STATE_PREFIX goes in front of the state hash where it can't have a key of its own (see [state_tag]).
+const STATE_PREFIX: &str = "zotify_genre_tagger_state=";
+
+/// state_tag returns the tag key the state hash is stored under in files written by the ffmpeg muxer `format_name`,
+/// and the prefix its value gets there. The MP4 family's muxers only write the keys they know, so there it goes under
+/// "keywords", which music players leave alone, prefixed with [STATE_PREFIX] to tell it apart from keywords the file
+/// already had.
+fn state_tag(format_name: &str) -> (&'static str, &'static str) {
+    match format_name {
+        "mov" | "mp4" | "ipod" | "3gp" | "3g2" | "psp" | "ismv" | "f4v" => ("keywords", STATE_PREFIX),
+        _ => (STATE_TAG, ""),
+    }
+}
+
+/// state_hash returns a hash of everything that determines what this tool writes to the file for `track_id`:
+/// the track, its sorted `genres`, and the way they're written (`genre_join`). If a file's [STATE_TAG] already
+/// matches, writing it again would change nothing.
+pub fn state_hash(track_id: &str, genres: &[String], genre_join: &GenreJoin) -> String {
+    let mut sorted_genres = genres.to_vec();
+    sorted_genres.sort();
+    let mut hasher = Sha256::new();
+    hasher.update("v1\0genre\0");
+    match genre_join {
+        GenreJoin::Delimiter(delimiter) => hasher.update(delimiter),
+        GenreJoin::MultiValue => hasher.update("\0multi"),
+    }
+    hasher.update("\0");
+    hasher.update(track_id);
+    for genre in sorted_genres {
+        hasher.update("\0");
+        hasher.update(genre);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// read_tag returns the value of the tag `key` of the song at `path`, looking in the same place
+/// (container or audio stream metadata) that [remux_with_genres] writes to.
+pub fn read_tag(path: &Path, key: &str) -> Result<Option<String>> {
+    Ok(read_tags(path, key)?.into_iter().next())
+}
+
+/// read_state returns the state hash [remux_with_genres] stored in the song at `path` (see [state_tag]), if it has one.
+pub fn read_state(path: &Path) -> Result<Option<String>> {
+    let ictx = format::input(path)?;
+    let (key, prefix) = state_tag(&muxer_name(path, &ictx)?);
+    Ok(tag_values(&ictx, key)
+        .into_iter()
+        .find_map(|value| value.strip_prefix(prefix).map(str::to_owned)))
+}
+
+/// read_tags returns every value of the tag `key` (compared case-insensitively, like ffmpeg does) of the song at
+/// `path`, in order, looking in the same place as [read_tag].
+fn read_tags(path: &Path, key: &str) -> Result<Vec<String>> {
+    Ok(tag_values(&format::input(path)?, key))
+}
+
+/// tag_values returns every value of the tag `key` in `ictx`, as [read_tags] does.
+fn tag_values(ictx: &Input, key: &str) -> Vec<String> {
+    let values = |metadata: DictionaryRef| {
+        metadata
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.to_owned())
+            .collect()
+    };
+    if ictx.metadata().iter().count() != 0 {
+        values(ictx.metadata())
+    } else {
+        ictx.streams()
+            .best(media::Type::Audio)
+            .map(|stream| values(stream.metadata()))
+            .unwrap_or_default()
+    }
+}
+
+/// verify_genres reads the genre tag of the song at `path` back and checks that it's `genres` written according to
+/// `genre_join`, catching tags that didn't stick (e.g. written where the container doesn't keep them).
+pub fn verify_genres(path: &Path, genres: &[String], genre_join: &GenreJoin) -> Result<()> {
+    let mut written = read_tags(path, "genre")?;
+    let mut expected = match genre_join {
+        GenreJoin::Delimiter(delimiter) => vec![genres.join(delimiter)],
+        // Repeated Vorbis comments are read back as one value, and other containers had them joined to begin with
+        GenreJoin::MultiValue => vec![genres.join(MERGED_DELIMITER)],
+    };
+    // An empty genre tag may or may not survive the muxer, and either way means no genres
+    written.retain(|genre| !genre.is_empty());
+    expected.retain(|genre| !genre.is_empty());
+    if written != expected {
+        return Err(anyhow!("Read back genre tag {written:?} instead of {expected:?}"));
+    }
+    Ok(())
+}
+
+/// temp_path_for returns the path the remuxed copy of `path` is written to before it replaces the original: `path`
+/// with ".tmp" appended, which works the same whether or not `path` has an extension.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+/// genre_key returns the genre tag key to write for the muxer named `format_name`.
+/// Vorbis comment containers get the conventional uppercase "GENRE"; everything else gets ffmpeg's generic "genre",
+/// which muxers with their own tag names translate (e.g. to "TCON" for ID3v2 and "©gen" for MP4).
+/// Reading tags back is case-insensitive, so either finds an existing genre tag.
+pub fn genre_key(format_name: &str) -> &'static str {
+    match is_vorbis_comment(format_name) {
+        true => "GENRE",
+        false => "genre",
+    }
+}
+
+/// stored_genre_join returns how genres meant to be written according to `genre_join` are actually written by the
+/// muxer named `format_name`. Only Vorbis comments can repeat a tag; ffmpeg's other muxers keep one value per key, so
+/// there multi-value genres are joined with [MERGED_DELIMITER] rather than all but one being dropped.
+fn stored_genre_join(format_name: &str, genre_join: &GenreJoin) -> GenreJoin {
+    match genre_join {
+        GenreJoin::MultiValue if !is_vorbis_comment(format_name) => GenreJoin::Delimiter(MERGED_DELIMITER.to_owned()),
+        _ => genre_join.clone(),
+    }
+}
+
+/// is_vorbis_comment returns whether files written by the muxer named `format_name` keep their tags as Vorbis comments.
+fn is_vorbis_comment(format_name: &str) -> bool {
+    matches!(format_name, "ogg" | "oga" | "opus" | "spx" | "flac")
+}
+
+/// with_genre_tag returns `metadata` with its genre tag replaced by `genres` under `key`, written according to
+/// `genre_join`.
+fn with_genre_tag(
+    mut metadata: Dictionary<'static>,
+    key: &str,
+    genres: &[String],
+    genre_join: &GenreJoin,
+) -> Result<Dictionary<'static>> {
+    match genre_join {
+        GenreJoin::Delimiter(delimiter) => {
+            metadata.set(key, &genres.join(delimiter));
+            Ok(metadata)
+        }
+        GenreJoin::MultiValue => {
+            // Dictionary::set always overwrites, so repeating a key needs AV_DICT_MULTIKEY through the FFI
+            let key = CString::new(key)?;
+            let values = genres
+                .iter()
+                .map(|genre| CString::new(genre.as_str()))
+                .collect::<Result<Vec<_>, _>>()?;
+            unsafe {
+                let mut raw = metadata.disown();
+                // Deleting only removes the first match, and the input may already have several
+                while !ffi::av_dict_get(raw, key.as_ptr(), ptr::null(), 0).is_null() {
+                    ffi::av_dict_set(&mut raw, key.as_ptr(), ptr::null(), 0);
+                }
+                for value in &values {
+                    ffi::av_dict_set(&mut raw, key.as_ptr(), value.as_ptr(), ffi::AV_DICT_MULTIKEY as _);
+                }
+                Ok(Dictionary::own(raw))
+            }
+        }
+    }
+}
+
+/// muxer_name returns the name of the ffmpeg muxer for files like the one at `path`, guessed from its extension,
+/// falling back to the first name of the demuxer `ictx` was opened with (e.g. "mov" for "mov,mp4,m4a,3gp,3g2,mj2").
+fn muxer_name(path: &Path, ictx: &Input) -> Result<String> {
+    let file_name = CString::new(path.file_name().unwrap_or_default().to_string_lossy().as_bytes())?;
+    unsafe {
+        let guessed = ffi::av_guess_format(ptr::null(), file_name.as_ptr(), ptr::null());
+        if !guessed.is_null() {
+            return Ok(CStr::from_ptr((*guessed).name).to_string_lossy().into_owned());
+        }
+    }
+    let demuxer_name = ictx.format().name();
+    Ok(demuxer_name.split(',').next().unwrap_or(demuxer_name).to_owned())
+}
+
+/// muxer_supports returns whether the muxer of `octx` can hold a stream of `codec`. Muxers answer with any positive
+/// value for yes (the mp3 one with the ID3 frame it would use), 0 for no, and a negative one if they can't tell.
+fn muxer_supports(octx: &Output, codec: codec::Id) -> bool {
+    unsafe { ffi::avformat_query_codec(octx.format().as_ptr(), codec.into(), ffi::FF_COMPLIANCE_NORMAL) > 0 }
+}
+
+/// picture_block returns the attached picture of `stream` as a base64 FLAC picture block, the form Vorbis comments
+/// (METADATA_BLOCK_PICTURE) hold cover art in. The picture type is "front cover".
+fn picture_block(stream: &Stream) -> Result<String> {
+    let mime_type = match stream.parameters().id() {
+        codec::Id::PNG => "image/png",
+        codec::Id::MJPEG => "image/jpeg",
+        codec::Id::GIF => "image/gif",
+        codec::Id::BMP => "image/bmp",
+        codec::Id::WEBP => "image/webp",
+        id => return Err(anyhow!("Unsupported cover art codec {id:?}")),
+    };
+    let description = stream.metadata().get("title").unwrap_or_default().to_owned();
+    let (width, height, data) = unsafe {
+        let parameters = *stream.parameters().as_ptr();
+        let attached_pic = &(*stream.as_ptr()).attached_pic;
+        let data = match attached_pic.data.is_null() {
+            true => &[][..],
+            false => slice::from_raw_parts(attached_pic.data, attached_pic.size as usize),
+        };
+        (parameters.width as u32, parameters.height as u32, data)
+    };
+    let mut block = Vec::with_capacity(32 + mime_type.len() + description.len() + data.len());
+    let mut push_u32 = |block: &mut Vec<u8>, n: u32| block.extend_from_slice(&n.to_be_bytes());
+    push_u32(&mut block, 3);
+    push_u32(&mut block, mime_type.len() as u32);
+    block.extend_from_slice(mime_type.as_bytes());
+    push_u32(&mut block, description.len() as u32);
+    block.extend_from_slice(description.as_bytes());
+    push_u32(&mut block, width);
+    push_u32(&mut block, height);
+    // Color depth and palette size, which readers ignore for non-indexed images
+    push_u32(&mut block, 24);
+    push_u32(&mut block, 0);
+    push_u32(&mut block, data.len() as u32);
+    block.extend_from_slice(data);
+    Ok(BASE64_STANDARD.encode(block))
+}
+
+/// remux_with_genres copies the streams (audio, cover art, and anything else the container can hold), chapters, and
+/// metadata of the song at `path` into `temp_path` with its genre tag set to `genres` (written according to
+/// `genre_join`) and its state hash set to `state_hash` (see [state_tag]), leaving the original untouched.
+/// Cover art going into an Ogg file, which can't hold picture streams, is written as a METADATA_BLOCK_PICTURE comment.
+/// The temp file has the same container and codec as the original, and the audio is copied through unchanged.
+/// Returns whether the genre tag is new or replaces one that was already there.
+pub fn remux_with_genres(
+    path: &Path,
+    temp_path: &Path,
+    genres: &[String],
+    genre_join: &GenreJoin,
+    state_hash: &str,
+) -> Result<ChangeKind> {
+    let mut ictx = format::input(path)?;
+    let best_audio = ictx.streams().best(media::Type::Audio);
+    let best_audio_index = best_audio.as_ref().map(Stream::index);
+    let context_or_stream = if ictx.metadata().iter().count() != 0 {
+        ContextOrStream::Context(&ictx)
+    } else {
+        ContextOrStream::Stream(
+            best_audio
+                .as_ref()
+                .ok_or_else(|| anyhow!("No audio stream in {}", path.display()))?,
+        )
+    };
+    let change_kind = match &context_or_stream {
+        ContextOrStream::Context(ictx) => ictx.metadata().get("genre"),
+        ContextOrStream::Stream(input) => input.metadata().get("genre"),
+    }
+    .map_or(ChangeKind::Add, |_| ChangeKind::Replace);
+    let muxer_name = muxer_name(path, &ictx)?;
+    let mut octx = format::output_as(temp_path, &muxer_name)?;
+    let key = genre_key(octx.format().name());
+    let genre_join = stored_genre_join(octx.format().name(), genre_join);
+    let (state_key, state_prefix) = state_tag(octx.format().name());
+    let state_value = format!("{state_prefix}{state_hash}");
+    let mut stream_mapping: Vec<i32> = vec![0; ictx.nb_streams() as _];
+    let mut ist_time_bases = vec![Rational(0, 1); ictx.nb_streams() as _];
+    let mut ost_index = 0;
+    let mut pictures = Vec::new();
+    for (ist_index, ist) in ictx.streams().enumerate() {
+        let is_audio = ist.parameters().medium() == media::Type::Audio;
+        let is_picture = ist.disposition().contains(Disposition::ATTACHED_PIC);
+        // Ogg has no picture streams; cover art lives in a METADATA_BLOCK_PICTURE comment instead
+        if is_picture && octx.format().name() == "ogg" {
+            stream_mapping[ist_index] = -1;
+            pictures.push(picture_block(&ist)?);
+            continue;
+        }
+        // Cover art isn't checked: muxers that hold it don't all list its codec (flac's only lists PNG, yet it takes
+        // JPEG), and it came out of the same container anyway
+        if !is_audio && !is_picture && !muxer_supports(&octx, ist.parameters().id()) {
+            stream_mapping[ist_index] = -1;
+            warn!(
+                "Dropping stream {ist_index} ({:?}) of {}: {muxer_name} can't hold it",
+                ist.parameters().id(),
+                path.display()
+            );
+            continue;
+        }
+        stream_mapping[ist_index] = ost_index;
+        ist_time_bases[ist_index] = ist.time_base();
+        ost_index += 1;
+        // No encoder: the stream's packets are copied as they are
+        let mut ost = octx.add_stream(encoder::find(codec::Id::None))?;
+        ost.set_parameters(ist.parameters());
+        ost.set_metadata(ist.metadata().to_owned());
+        unsafe {
+            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+            // Keeps cover art marked as an attached picture rather than a one-frame video
+            (*ost.as_mut_ptr()).disposition = (*ist.as_ptr()).disposition;
+        }
+    }
+    // Chapters (e.g. in long-form audio) aren't copied by the muxer on its own
+    for chapter in ictx.chapters() {
+        let title = chapter.metadata().get("title").unwrap_or_default().to_owned();
+        let mut ochapter =
+            octx.add_chapter(chapter.id(), chapter.time_base(), chapter.start(), chapter.end(), &title)?;
+        for (key, value) in chapter.metadata().iter() {
+            ochapter.set_metadata(key, value);
+        }
+    }
+    // Both levels of metadata are carried over as they were; the genre goes wherever the input keeps its tags
+    match context_or_stream {
+        ContextOrStream::Context(ictx) => {
+            let mut octx_metadata = with_genre_tag(ictx.metadata().to_owned(), key, genres, &genre_join)?;
+            octx_metadata.set(state_key, &state_value);
+            octx.set_metadata(octx_metadata);
+        }
+        ContextOrStream::Stream(input) => {
+            octx.set_metadata(ictx.metadata().to_owned());
+            let ost_index = stream_mapping[input.index()];
+            let mut output = octx
+                .stream_mut(ost_index as _)
+                .ok_or_else(|| anyhow!("No audio stream in output for {}", path.display()))?;
+            let mut output_metadata = with_genre_tag(input.metadata().to_owned(), key, genres, &genre_join)?;
+            output_metadata.set(state_key, &state_value);
+            output.set_metadata(output_metadata);
+        }
+    }
+
+    if let (Some(picture), Some(best_audio_index)) = (pictures.first(), best_audio_index) {
+        let mut output = octx
+            .stream_mut(stream_mapping[best_audio_index] as _)
+            .ok_or_else(|| anyhow!("No audio stream in output for {}", path.display()))?;
+        let mut output_metadata = output.metadata().to_owned();
+        output_metadata.set("METADATA_BLOCK_PICTURE", picture);
+        output.set_metadata(output_metadata);
+    }
+
+    octx.write_header()?;
+
+    for (stream, mut packet) in ictx.packets() {
+        let ist_index = stream.index();
+        let ost_index = stream_mapping[ist_index];
+        if ost_index < 0 {
+            continue;
+        }
+        let ost = octx
+            .stream(ost_index as _)
+            .ok_or_else(|| anyhow!("Missing output stream {ost_index}"))?;
+        packet.rescale_ts(ist_time_bases[ist_index], ost.time_base());
+        packet.set_position(-1);
+        packet.set_stream(ost_index as _);
+        packet.write_interleaved(&mut octx)?;
+    }
+
+    octx.write_trailer()?;
+
+    Ok(change_kind)
+}
+
+/// backup_path returns where [backup_original] backs `path` up to.
+fn backup_path(path: &Path, base_path: &Path, backup_dir: &Path) -> Result<PathBuf> {
+    let relative_path = path
+        .strip_prefix(base_path)
+        .ok()
+        .or_else(|| path.file_name().map(Path::new))
+        .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+    Ok(backup_dir.join(relative_path))
+}
+
+/// restore_backup puts the backup [backup_original] made of `path` back in its place, through a temp file so `path`
+/// is never left half-written.
+pub fn restore_backup(path: &Path, base_path: &Path, backup_dir: &Path) -> Result<()> {
+    let backup = backup_path(path, base_path, backup_dir)?;
+    let temp_path = temp_path_for(path);
+    fs::copy(&backup, &temp_path)?;
+    fs::File::open(&temp_path)?.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// backup_original copies the original at `path` into `backup_dir`, keeping its path relative to `base_path` (or just
+/// its file name if it isn't under `base_path`). An existing backup is kept rather than overwritten, since it's the
+/// older and so more original copy.
+pub fn backup_original(path: &Path, base_path: &Path, backup_dir: &Path) -> Result<()> {
+    let destination = backup_path(path, base_path, backup_dir)?;
+    if destination.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Copy under a temp name first so a half-copied backup is never mistaken for a complete one
+    let temp_destination = temp_path_for(&destination);
+    fs::copy(path, &temp_destination)?;
+    fs::File::open(&temp_destination)?.sync_all()?;
+    fs::rename(&temp_destination, &destination)?;
+    Ok(())
+}
+
+/// write_genre_tag tags the song at `path` with `genres` according to `opts`: unless its [STATE_TAG] shows it's already
+/// tagged, it backs up the original, remuxes it into a temp file with [remux_with_genres], and replaces the original
+/// with it. A failure is returned with the [Phase] it happened in; a failed remux leaves no temp file behind.
+pub fn write_genre_tag(
+    path: &Path,
+    genres: &[String],
+    opts: &WriteOpts,
+) -> Result<WriteOutcome, (Phase, anyhow::Error)> {
+    let mount = opts.mount;
+    let state_hash = state_hash(opts.track_id, genres, opts.genre_join);
+    if opts.only_untouched || !opts.ignore_state_hash {
+        let skip_reason = match mount.retry("Reading the state hash", || read_state(path)) {
+            Ok(Some(_)) if opts.only_untouched => Some("already tagged by this tool"),
+            Ok(Some(existing_hash)) if !opts.ignore_state_hash && existing_hash == state_hash => {
+                Some("already tagged with these genres")
+            }
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Couldn't read the state hash of {}: {e}", path.display());
+                None
+            }
+        };
+        if let Some(skip_reason) = skip_reason {
+            return Ok(WriteOutcome::Skipped(skip_reason));
+        }
+    }
+    info!("Processing file {}", path.display());
+    if let Some(backup_dir) = opts.backup_dir {
+        mount
+            .retry("Backing up", || backup_original(path, opts.base_path, backup_dir))
+            .map_err(|e| (Phase::Backup, e))?;
+    }
+    let temp_path = temp_path_for(path);
+    let stage = || -> Result<(Option<String>, ChangeKind)> {
+        let old_hash = match opts.hash_original {
+            true => Some(mount.retry("Hashing", || changes::hash_file(path))?),
+            false => None,
+        };
+        let kind = mount.retry("Remuxing", || {
+            retry_transient(opts.fs_retries, || {
+                remux_with_genres(path, &temp_path, genres, opts.genre_join, &state_hash)
+            })
+        })?;
+        Ok((old_hash, kind))
+    };
+    let (old_hash, kind) = stage().map_err(|e| {
+        let _ = fs::remove_file(&temp_path);
+        (Phase::Remux, e)
+    })?;
+    if opts.stage_only {
+        return Ok(WriteOutcome::Staged {
+            temp_path,
+            old_hash,
+            kind,
+        });
+    }
+    mount
+        .retry("Replacing the original", || replace_original(path, &temp_path, opts.fs_retries))
+        .map_err(|e| (Phase::Replace, e))?;
+    Ok(WriteOutcome::Tagged { old_hash, kind })
+}
+
+/// verify_temp flushes the remuxed file at `temp_path` to disk and checks that ffmpeg can open it and finds an audio
+/// stream, so a partially written file never replaces a good original.
+fn verify_temp(temp_path: &Path) -> Result<()> {
+    fs::File::open(temp_path)?.sync_all()?;
+    let ictx = format::input(temp_path)?;
+    if ictx.streams().best(media::Type::Audio).is_none() {
+        return Err(anyhow!("Remuxed file {} has no audio stream", temp_path.display()));
+    }
+    Ok(())
+}
+
+/// replace_original moves the remuxed file at `temp_path` over the original at `path` once it's verified, retrying
+/// each step up to `fs_retries` times on transient errors.
+/// The rename replaces the original in one step, so a crash leaves either the original or the remuxed file in place,
+/// never neither. Only if renaming over the original fails is it deleted first.
+pub fn replace_original(path: &Path, temp_path: &Path, fs_retries: u32) -> Result<()> {
+    verify_temp(temp_path)?;
+    if let Err(e) = retry_transient(fs_retries, || Ok(fs::rename(temp_path, path)?)) {
+        warn!("Couldn't rename over {} ({e}), deleting it first", path.display());
+        retry_transient(fs_retries, || Ok(fs::remove_file(path)?))?;
+        retry_transient(fs_retries, || Ok(fs::rename(temp_path, path)?))?;
+    }
+    // Make the rename itself durable, not just the file's contents
+    #[cfg(unix)]
+    if let Some(folder) = path.parent().filter(|folder| !folder.as_os_str().is_empty()) {
+        fs::File::open(folder)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// is_transient returns whether `e` is the kind of error that tends to go away on its own, like a file briefly
+/// locked by an antivirus scanner or a network filesystem hiccup, as opposed to e.g. a missing file or a
+/// read-only filesystem, which retrying won't fix.
+/// Both [io::Error]s and ffmpeg errors carrying an errno are recognized.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    let kind = if let Some(e) = e.downcast_ref::<io::Error>() {
+        e.kind()
+    } else if let Some(ffmpeg_next::Error::Other { errno }) = e.downcast_ref::<ffmpeg_next::Error>() {
+        io::Error::from_raw_os_error(*errno).kind()
+    } else {
+        return false;
+    };
+    matches!(
+        kind,
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ResourceBusy
+    ) || (cfg!(windows) && kind == ErrorKind::PermissionDenied)
+}
+
+/// retry_transient runs `op`, retrying up to `retries` times with a doubling delay (starting at 100ms)
+/// while it fails with a [transient](is_transient) error. Any other error is returned immediately.
+pub fn retry_transient<T>(retries: u32, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = Duration::from_millis(100) * 2u32.saturating_pow(attempt);
+                warn!("Transient filesystem error, retrying in {delay:?}: {e}");
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// remux_fixture remuxes a copy of the fixture `name` with the genres "Rock" and "Pop", returning the folder it's
+    /// in (deleted on drop) and the path of the remuxed file.
+    fn remux_fixture(name: &str) -> (tempfile::TempDir, PathBuf) {
+        ffmpeg_next::init().unwrap();
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join(name);
+        fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name), &path).unwrap();
+        let temp_path = temp_path_for(&path);
+        let genres = vec!["Rock".to_owned(), "Pop".to_owned()];
+        remux_with_genres(&path, &temp_path, &genres, &GenreJoin::Delimiter(";".to_owned()), "hash").unwrap();
+        (folder, temp_path)
+    }
+
+    /// round_trip remuxes the fixture `name` with [remux_fixture], then returns the genre tag read back from the
+    /// remuxed file along with its raw bytes.
+    fn round_trip(name: &str) -> (Option<String>, Vec<u8>) {
+        let (_folder, temp_path) = remux_fixture(name);
+        (read_tag(&temp_path, "genre").unwrap(), fs::read(&temp_path).unwrap())
+    }
+
+    /// contains returns whether `needle` appears anywhere in `haystack`.
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn ogg_genre_is_a_vorbis_comment() {
+        let (genre, bytes) = round_trip("stream.opus");
+        assert_eq!(genre.as_deref(), Some("Rock;Pop"));
+        assert!(contains(&bytes, b"GENRE=Rock;Pop"));
+    }
+
+    #[test]
+    fn flac_genre_is_a_vorbis_comment() {
+        let (genre, bytes) = round_trip("container.flac");
+        assert_eq!(genre.as_deref(), Some("Rock;Pop"));
+        assert!(contains(&bytes, b"GENRE=Rock;Pop"));
+    }
+
+    #[test]
+    fn mp3_genre_is_a_tcon_frame() {
+        let (genre, bytes) = round_trip("container.mp3");
+        assert_eq!(genre.as_deref(), Some("Rock;Pop"));
+        assert!(contains(&bytes, b"TCON"));
+    }
+
+    #[test]
+    fn m4a_genre_is_a_gen_atom() {
+        let (genre, bytes) = round_trip("container.m4a");
+        assert_eq!(genre.as_deref(), Some("Rock;Pop"));
+        assert!(contains(&bytes, b"\xa9gen"));
+    }
+
+    /// attached_picture returns the data of the first attached picture in the file at `path`, if it has one.
+    fn attached_picture(path: &Path) -> Option<Vec<u8>> {
+        let ictx = format::input(path).unwrap();
+        let stream = ictx.streams().find(|stream| stream.disposition().contains(Disposition::ATTACHED_PIC))?;
+        unsafe {
+            let attached_pic = &(*stream.as_ptr()).attached_pic;
+            Some(slice::from_raw_parts(attached_pic.data, attached_pic.size as usize).to_vec())
+        }
+    }
+
+    /// assert_cover_survives remuxes the fixture `name`, which has JPEG cover art, and checks the remuxed file still
+    /// has the same picture.
+    fn assert_cover_survives(name: &str) {
+        let (_folder, temp_path) = remux_fixture(name);
+        let original = attached_picture(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name));
+        let original = original.expect("fixture has no cover art");
+        assert!(original.starts_with(b"\xff\xd8"));
+        assert_eq!(attached_picture(&temp_path), Some(original));
+    }
+
+    #[test]
+    fn mp3_jpeg_cover_survives_tagging() {
+        assert_cover_survives("cover.mp3");
+    }
+
+    #[test]
+    fn flac_jpeg_cover_survives_tagging() {
+        assert_cover_survives("cover.flac");
+    }
+
+    #[test]
+    fn m4a_state_hash_reads_back() {
+        let (_folder, temp_path) = remux_fixture("container.m4a");
+        assert_eq!(read_state(&temp_path).unwrap().as_deref(), Some("hash"));
+        assert!(contains(&fs::read(&temp_path).unwrap(), b"zotify_genre_tagger_state=hash"));
+    }
+
+    #[test]
+    fn chapters_survive_a_remux() {
+        let (_folder, temp_path) = remux_fixture("chapters.mka");
+        let ictx = format::input(&temp_path).unwrap();
+        let chapters: Vec<_> = ictx
+            .chapters()
+            .map(|chapter| {
+                let millis = |ts: i64| {
+                    ts * 1000 * chapter.time_base().numerator() as i64 / chapter.time_base().denominator() as i64
+                };
+                (
+                    chapter.metadata().get("title").map(str::to_owned),
+                    millis(chapter.start()),
+                    millis(chapter.end()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            chapters,
+            vec![(Some("Intro".to_owned()), 0, 200), (Some("Outro".to_owned()), 200, 500)]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Tagging the fixture files end to end, checking the genre lands where the file keeps the rest of its tags.
+
+use ffmpeg_next::{format, media};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tempfile::TempDir;
+use zotify_genre_tagger::genres::GenreJoin;
+use zotify_genre_tagger::mount::MountGuard;
+use zotify_genre_tagger::write::{self, WriteOpts, WriteOutcome};
+
+/// write tags the file at `path` with `genres` joined by `genre_join`, returning what [write::write_genre_tag] did.
+fn write(path: &Path, genres: &[&str], genre_join: &GenreJoin, only_untouched: bool) -> WriteOutcome {
+    let folder = path.parent().unwrap();
+    let mount = MountGuard::new(folder.to_path_buf(), 0, Duration::ZERO);
+    let opts = WriteOpts {
+        track_id: "0000000000000000000001",
+        genre_join,
+        mount: &mount,
+        only_untouched,
+        ignore_state_hash: false,
+        backup_dir: None,
+        base_path: folder,
+        hash_original: false,
+        fs_retries: 0,
+        stage_only: false,
+    };
+    let genres: Vec<String> = genres.iter().map(|genre| genre.to_string()).collect();
+    write::write_genre_tag(path, &genres, &opts).map_err(|(_, e)| e).unwrap()
+}
+
+/// copy_fixture copies the fixture `name` into a temp folder, returning the folder (deleted on drop) and the path of
+/// the copy.
+fn copy_fixture(name: &str) -> (TempDir, PathBuf) {
+    ffmpeg_next::init().unwrap();
+    let folder = tempfile::tempdir().unwrap();
+    let path = folder.path().join(name);
+    fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name), &path).unwrap();
+    (folder, path)
+}
+
+/// tag copies the fixture `name` into a temp folder and tags it with the genres "Rock" and "Pop", returning the folder
+/// (deleted on drop) and the path of the tagged copy.
+fn tag(name: &str) -> (TempDir, PathBuf) {
+    let (folder, path) = copy_fixture(name);
+    let outcome = write(&path, &["Rock", "Pop"], &GenreJoin::Delimiter(";".to_owned()), false);
+    assert!(matches!(outcome, WriteOutcome::Tagged { .. }));
+    (folder, path)
+}
+
+/// tags returns the genre and title in the container metadata and in the best audio stream's metadata of the file at
+/// `path`, in that order.
+fn tags(path: &Path) -> [(Option<String>, Option<String>); 2] {
+    let ictx = format::input(path).unwrap();
+    let stream = ictx.streams().best(media::Type::Audio).unwrap();
+    let genre_and_title = |metadata: ffmpeg_next::DictionaryRef| {
+        (metadata.get("genre").map(str::to_owned), metadata.get("title").map(str::to_owned))
+    };
+    [genre_and_title(ictx.metadata()), genre_and_title(stream.metadata())]
+}
+
+/// genre returns the genre of the file at `path` from wherever [tags] finds it.
+fn genre(path: &Path) -> Option<String> {
+    let [(container, _), (stream, _)] = tags(path);
+    container.or(stream)
+}
+
+/// contains returns whether `needle` appears anywhere in `haystack`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[test]
+fn genre_goes_in_the_container_when_it_has_the_tags() {
+    let (_folder, path) = tag("container.mp3");
+    let [container, stream] = tags(&path);
+    assert_eq!(container, (Some("Rock;Pop".to_owned()), Some("Container".to_owned())));
+    assert_eq!(stream, (None, None));
+}
+
+#[test]
+fn genre_goes_in_the_stream_when_only_it_has_tags() {
+    let (_folder, path) = tag("stream.opus");
+    let [container, stream] = tags(&path);
+    assert_eq!(container, (None, None));
+    assert_eq!(stream, (Some("Rock;Pop".to_owned()), Some("Stream".to_owned())));
+}
+
+#[test]
+fn only_untouched_skips_a_tagged_m4a() {
+    let (_folder, path) = tag("container.m4a");
+    let outcome = write(&path, &["Jazz"], &GenreJoin::Delimiter(";".to_owned()), true);
+    assert!(matches!(outcome, WriteOutcome::Skipped("already tagged by this tool")));
+    assert_eq!(tags(&path)[0].0.as_deref(), Some("Rock;Pop"));
+}
+
+#[test]
+fn retagging_an_m4a_with_the_same_genres_is_skipped() {
+    let (_folder, path) = tag("container.m4a");
+    let outcome = write(&path, &["Rock", "Pop"], &GenreJoin::Delimiter(";".to_owned()), false);
+    assert!(matches!(outcome, WriteOutcome::Skipped("already tagged with these genres")));
+}
+
+#[test]
+fn multi_value_vorbis_comments_pass_verification_with_a_backup() {
+    for name in ["stream.opus", "container.flac"] {
+        let (folder, path) = copy_fixture(name);
+        let backup_dir = folder.path().join("backup");
+        let mount = MountGuard::new(folder.path().to_path_buf(), 0, Duration::ZERO);
+        let opts = WriteOpts {
+            track_id: "0000000000000000000001",
+            genre_join: &GenreJoin::MultiValue,
+            mount: &mount,
+            only_untouched: false,
+            ignore_state_hash: false,
+            backup_dir: Some(&backup_dir),
+            base_path: folder.path(),
+            hash_original: false,
+            fs_retries: 0,
+            stage_only: false,
+        };
+        let genres = vec!["Rock".to_owned(), "Pop".to_owned()];
+        let outcome = write::write_genre_tag(&path, &genres, &opts).map_err(|(_, e)| e).unwrap();
+        assert!(matches!(outcome, WriteOutcome::Tagged { .. }));
+        assert!(backup_dir.join(name).exists());
+        // A mismatch here is what would make --verify restore the backup over the tagged file
+        write::verify_genres(&path, &genres, &GenreJoin::MultiValue).unwrap();
+    }
+}
+
+#[test]
+fn vorbis_comment_files_take_multi_value_and_custom_delimiters() {
+    for name in ["stream.opus", "container.flac"] {
+        let (_folder, path) = copy_fixture(name);
+        write(&path, &["Rock", "Pop"], &GenreJoin::MultiValue, false);
+        let bytes = fs::read(&path).unwrap();
+        assert!(contains(&bytes, b"GENRE=Rock") && contains(&bytes, b"GENRE=Pop"), "{name}");
+        assert!(!contains(&bytes, b"GENRE=Rock;Pop"), "{name}");
+        // ffmpeg reads the repeated comments back as one value
+        assert_eq!(genre(&path).as_deref(), Some("Rock;Pop"), "{name}");
+
+        let (_folder, path) = copy_fixture(name);
+        write(&path, &["Rock", "Pop"], &GenreJoin::Delimiter(" / ".to_owned()), false);
+        assert!(contains(&fs::read(&path).unwrap(), b"GENRE=Rock / Pop"), "{name}");
+        assert_eq!(genre(&path).as_deref(), Some("Rock / Pop"), "{name}");
+    }
+}
+
+#[test]
+fn id3_and_mp4_files_take_multi_value_and_custom_delimiters() {
+    for name in ["container.mp3", "container.m4a"] {
+        let (_folder, path) = copy_fixture(name);
+        write(&path, &["Rock", "Pop"], &GenreJoin::MultiValue, false);
+        // Neither can repeat the tag, so the genres are joined rather than all but one dropped
+        assert_eq!(genre(&path).as_deref(), Some("Rock;Pop"), "{name}");
+
+        let (_folder, path) = copy_fixture(name);
+        write(&path, &["Rock", "Pop"], &GenreJoin::Delimiter(" / ".to_owned()), false);
+        assert_eq!(genre(&path).as_deref(), Some("Rock / Pop"), "{name}");
+    }
+}